@@ -1,206 +1,2539 @@
 import threading
+import collections
 import json
+import queue
 import time
 import ipaddress
 import yaml
 import datetime
 import argparse
 import logging
+import logging.handlers
 import sys
+import ssl
+import hmac
+import base64
+import signal
+import socket
+import socketserver
+import os
+import glob
+import copy
+import tomllib
+import difflib
+import re
+import random
+import uuid
+import hashlib
+import traceback
+import sqlite3
+import dataclasses
+import math
+import struct
+import html
+import email.utils
 logging.basicConfig(format='%(asctime)s - %(levelname)s - %(message)s', level=logging.INFO)
 logger = logging.getLogger(__name__)
 
 from ipgetter2 import IPGetter
+import urllib.parse
 from urllib.request import Request, urlopen
-from http.server import HTTPServer, BaseHTTPRequestHandler
-from prometheus_client import Gauge, Info, Enum, generate_latest, CollectorRegistry
+from http.server import ThreadingHTTPServer, BaseHTTPRequestHandler
+from prometheus_client import Counter, Gauge, Info, Enum, generate_latest, CollectorRegistry, push_to_gateway, ProcessCollector, PlatformCollector, GCCollector
 
 parser = argparse.ArgumentParser()
-parser.add_argument('--config', '-c', type=str, default='config.yml', help='Path to the configuration file')
+parser.add_argument('--config', '-c', type=str, default='config.yml', help='Path to the configuration file (.yml, .json or .toml)')
+parser.add_argument('--profile', type=str, default=os.environ.get('CCS_PROFILE'), help='Also apply the YAML documents of the config with this profile and the config.<profile>.yml next to it, e.g. staging (defaults to CCS_PROFILE)')
+parser.add_argument('--watch_config', action='store_true', help='Reload the configuration file once it changed (an alternative to SIGHUP, e.g. on Windows)')
 parser.add_argument('--debug', '-d', action='store_true', help='Something does not work? Debug mode!')
 parser.add_argument('--port', '-p', type=int, default=80, help='Port for the internal healthcheck/metrics-endpoint')
+parser.add_argument('--bind', '-b', type=str, action='append', help='Listen on host:port, [ipv6]:port or unix:/path/to/socket instead of --port (can be given multiple times)')
 parser.add_argument('--metrics_prefix', type=str, default='ccs', help='Prefix for all metrics provided by this exporter')
+parser.add_argument('--simulate', type=str, help='Run the checks of this scenario file without touching Cloudflare or Telegram (see README)')
+parser.add_argument('--shadow_config', type=str, help='Also evaluate the subnets and confidence of this (candidate) configuration file, but only report its decisions')
 args = parser.parse_args()
 
 if args.debug:
     logging.basicConfig(format='%(asctime)s - %(levelname)s - %(message)s', level=logging.DEBUG, force=True)
 
-# Config stuff
-logger.debug('Loading config...')
-with open(args.config, 'r') as configFile:
-    config = yaml.safe_load(configFile)
+version = os.environ.get('CCS_VERSION', 'dev') # Set by the Docker image
+chaosSettings = {'enabled': False} # Replaced by the chaos section of the config once it is loaded
+decisionHistory = collections.deque() # Every decision to change the dynamic_cname, resized once the config is loaded
+checkHistory = collections.deque() # Result of every check, resized once the config is loaded
+wakeUp = threading.Event() # Interrupts the sleep of the main loop
+shutdownRequested = threading.Event() # Set by SIGTERM/SIGINT, lets the main loop and all /events streams finish (and stops retries, even while loading the config)
 
-# Stuff, which should be set, when the user is not using the sample-config anymore...
-assert config['cloudflare']['zone_id'], 'cloudflare.zone_id should be given'
-assert config['cloudflare']['token'], 'cloudflare.token should be given'
-assert config['general']['dynamic_cname'], 'general.dynamic_cname should be given'
-assert config['primary']['cname'], 'primary.cname should be given'
-assert config['secondary']['cname'], 'secondary.cname should be given'
-assert len(config['primary']['subnets']) > 0 or len(config['secondary']['subnets']) > 0, 'primary or secondary subnets should be given'
+def identifyRequest(request, config):
+    # Lets the receiving side (e.g. its logs) tell which switcher instance sent the request
+    request.add_header('User-Agent', config['general']['user_agent'] or f'cloudflare-cname-switcher/{version}')
+    if config['general']['request_id']:
+        requestId = str(uuid.uuid4())
+        request.add_header('X-Request-Id', requestId)
+        logger.debug(f'{request.get_method()} {urllib.parse.urlsplit(request.full_url).netloc} as request {requestId}')
+    return request
+
+def openUrl(config, method, url, data=None, headers={}, context=None):
+    # Single way to send a request anywhere (a dict as data is sent as JSON), so every integration gets the same identification and timeout
+    request = Request(url, method=method, headers=headers)
+    if data is not None:
+        request.data = bytes(json.dumps(data), encoding='utf8')
+        request.add_header('Content-Type', 'application/json')
+    return urlopen(identifyRequest(request, config), timeout=config['general']['timeout'], context=context)
+
+def injectChaos(probabilitySetting, message):
+    # Fault injection for resilience testing, only active if enabled in the config
+    if chaosSettings['enabled'] and random.random() < chaosSettings[probabilitySetting]:
+        raise ConnectionError('Chaos: ' + message)
+
+cloudflareAuditLogger = logging.getLogger('cloudflare_audit') # Only written to cloudflare.audit_file
+cloudflareAuditLogger.propagate = False
+cloudflareAuditLogger.setLevel(logging.INFO)
+cloudflareAuditLock = threading.Lock()
+
+def auditCloudflareCall(config, entry):
+    # Proves what was changed at Cloudflare and when - the token is never part of an entry
+    if config['cloudflare']['audit_file'] is None:
+        return
+    with cloudflareAuditLock:
+        handler = cloudflareAuditLogger.handlers[0] if len(cloudflareAuditLogger.handlers) else None
+        if handler is None or handler.baseFilename != os.path.abspath(config['cloudflare']['audit_file']) or handler.maxBytes != config['cloudflare']['audit_max_bytes'] or handler.backupCount != config['cloudflare']['audit_backups']:
+            if handler is not None:
+                cloudflareAuditLogger.removeHandler(handler)
+                handler.close()
+            handler = logging.handlers.RotatingFileHandler(config['cloudflare']['audit_file'], maxBytes=config['cloudflare']['audit_max_bytes'], backupCount=config['cloudflare']['audit_backups'])
+            cloudflareAuditLogger.addHandler(handler)
+    cloudflareAuditLogger.info(json.dumps(entry))
+
+def classifyHttpError(e):
+    # Returns (retryable, seconds the server asked us to wait or None) for an exception of urlopen()
+    status = getattr(e, 'code', None)
+    retryAfter = None
+    headers = getattr(e, 'headers', None)
+    if headers is not None and headers.get('Retry-After'):
+        try:
+            retryAfter = max(0, float(headers['Retry-After']))
+        except ValueError:
+            try:
+                retryAfter = max(0, (email.utils.parsedate_to_datetime(headers['Retry-After']) - datetime.datetime.now(datetime.timezone.utc)).total_seconds())
+            except (TypeError, ValueError):
+                pass
+    if isinstance(status, int):
+        return status in (408, 425, 429, 500, 502, 503, 504), retryAfter
+    return isinstance(e, OSError), retryAfter # No response at all, e.g. a connect timeout
+
+class CloudflareApiError(Exception):
+    def __init__(self, message, status, retryable, retryAfter):
+        super().__init__(message)
+        self.status = status
+        self.retryable = retryable
+        self.retryAfter = retryAfter
+
+def withRetries(config, description, action, classify=classifyHttpError):
+    # The one retry policy (retry section) for resolving the external IP, Cloudflare and Vault - classify returns (retryable, retryAfter)
+    policy = config['retry']
+    for attempt in range(1, policy['attempts'] + 1):
+        try:
+            return action()
+        except Exception as e:
+            retryable, retryAfter = classify(e)
+            if attempt == policy['attempts'] or (policy['retry_on'] == 'retryable' and not retryable) or shutdownRequested.is_set():
+                raise
+            delay = policy['delay'] * (2 ** (attempt - 1) if policy['backoff'] == 'exponential' else 1)
+            delay = max(min(delay, policy['max_delay']) * random.uniform(1 - policy['jitter'], 1 + policy['jitter']), retryAfter or 0)
+            logger.warning(f'{description} failed (attempt {attempt} of {policy["attempts"]}), retrying in {delay:.1f}s: {e}')
+            shutdownRequested.wait(delay)
+
+cloudflareBudget = {'tokens': None, 'refill': None, 'waited': 0.0, 'blockedUntil': 0} # Token bucket shared by all Cloudflare calls
+cloudflareBudgetLock = threading.Lock()
+
+def waitForCloudflareBudget(config):
+    # Cloudflare allows 1200 requests per 5 minutes and account, so never exceed requests_per_minute (but allow bursts of a minute)
+    rate = config['cloudflare']['requests_per_minute'] / 60
+    now = time.monotonic()
+    with cloudflareBudgetLock:
+        if cloudflareBudget['tokens'] is None:
+            cloudflareBudget['tokens'] = config['cloudflare']['requests_per_minute']
+        else:
+            cloudflareBudget['tokens'] = min(config['cloudflare']['requests_per_minute'], cloudflareBudget['tokens'] + (now - cloudflareBudget['refill']) * rate)
+        cloudflareBudget['refill'] = now
+        cloudflareBudget['tokens'] -= 1 # Reserved, even if we have to wait for it
+        wait = max(0, -cloudflareBudget['tokens'] / rate, cloudflareBudget['blockedUntil'] - now)
+        cloudflareBudget['waited'] += wait
+    if wait > 0:
+        logger.warning(f'Cloudflare request budget exhausted, waiting {wait:.1f}s...')
+        time.sleep(wait)
+
+@dataclasses.dataclass
+class DnsRecord:
+    # A record as returned by Cloudflare, checked once so a surprising response fails right here with a clear error
+    id: str
+    name: str
+    type: str
+    content: str
+    ttl: int = 1 # 1 means automatic
+    proxied: bool = False
+    priority: int | None = None # Only for MX
+    comment: str | None = None
+    tags: list[str] = dataclasses.field(default_factory=list)
+
+    @classmethod
+    def fromApi(cls, data):
+        if not isinstance(data, dict):
+            raise CloudflareApiError(f'Cloudflare returned a {type(data).__name__} instead of a DNS record', None, False, None)
+        known = {key: value for key, value in data.items() if key in dnsRecordSchema and value is not None} # E.g. zone_id or created_on are not needed
+        problems = [f'{key}: missing' for key in ('id', 'name', 'type', 'content') if key not in known] + validateConfig(known, dnsRecordSchema)
+        if len(problems):
+            raise CloudflareApiError(f'Cloudflare returned an unexpected DNS record ({data.get("name", data.get("id"))}): {", ".join(problems)}', None, False, None)
+        return cls(**known)
+
+# Types of the record fields used here, checked like the config (see validateConfig)
+dnsRecordSchema = {
+    'id': ((str,), None),
+    'name': ((str,), None),
+    'type': ((str,), None),
+    'content': ((str,), None),
+    'ttl': ((int,), None),
+    'proxied': ((bool,), None),
+    'priority': ((int,), None),
+    'comment': ((str,), None),
+    'tags': ((list,), None)
+}
+
+class CloudflareApi:
+    # Everything talking to Cloudflare goes through here, so it can be replaced as a whole (e.g. for offline runs)
+    def __init__(self, config):
+        self.zoneId = config['cloudflare']['zone_id']
+        self.token = config['cloudflare']['token']
+        self.config = config
+        self.baseUrl = config['cloudflare']['api_url'].rstrip('/')
+        self.tlsContext = None
+        if config['cloudflare']['client_cert'] is not None or config['cloudflare']['ca_file'] is not None:
+            # E.g. for an egress gateway requiring a client certificate, or one using an internal CA
+            self.tlsContext = ssl.create_default_context(cafile=config['cloudflare']['ca_file'])
+            if config['cloudflare']['client_cert'] is not None:
+                self.tlsContext.load_cert_chain(config['cloudflare']['client_cert'], config['cloudflare']['client_key'])
+
+    def request(self, method, path, data=None):
+        return withRetries(self.config, 'Cloudflare request', lambda: self.requestOnce(method, path, data), lambda e: (e.retryable, None)) # Retry-After is already honored by the budget
+
+    def requestOnce(self, method, path, data):
+        waitForCloudflareBudget(self.config)
+        started = time.monotonic()
+        status, error = None, None
+        try:
+            injectChaos('cloudflare_failure_probability', f'Injected Cloudflare API failure ({method} {path})')
+            with openUrl(self.config, method, self.baseUrl + path, data, {'Authorization': 'Bearer ' + self.token}, self.tlsContext) as response:
+                status = response.status
+                return json.load(response)['result']
+        except Exception as e:
+            status, error = getattr(e, 'code', None), str(e)
+            retryable, retryAfter = classifyHttpError(e)
+            if retryAfter is not None:
+                with cloudflareBudgetLock:
+                    cloudflareBudget['blockedUntil'] = max(cloudflareBudget['blockedUntil'], time.monotonic() + retryAfter) # Honored by all further calls
+            raise CloudflareApiError(f'{method} {path.split("?")[0]} failed ({"retryable" if retryable else "not retryable"}): {error}', status, retryable, retryAfter) from e
+        finally:
+            auditCloudflareCall(self.config, {
+                'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+                'method': method,
+                'path': path,
+                'data': data,
+                'status': status,
+                'success': error is None,
+                'error': None if error is None else error.replace(self.token, '<redacted>'),
+                'duration': time.monotonic() - started
+            })
+
+    def listRecords(self, name, recordType):
+        records = self.request('GET', f'/zones/{self.zoneId}/dns_records?' + urllib.parse.urlencode({'name': name, 'type': recordType}))
+        if not isinstance(records, list):
+            raise CloudflareApiError(f'Cloudflare returned a {type(records).__name__} instead of a list of DNS records', None, False, None)
+        return [DnsRecord.fromApi(record) for record in records]
+
+    def updateRecord(self, recordId, data):
+        return DnsRecord.fromApi(self.request('PUT', f'/zones/{self.zoneId}/dns_records/{recordId}', data))
+
+    def verifyToken(self):
+        # Raises if the token can't be used, instead of noticing it at the next failover
+        token = self.request('GET', '/user/tokens/verify')
+        if token.get('status') != 'active':
+            raise PermissionError(f'the token is {token.get("status")}')
+        zone = self.request('GET', f'/zones/{self.zoneId}')
+        permissions = zone.get('permissions') # Not reported for every kind of token
+        if permissions is not None and '#dns_records:edit' not in permissions:
+            raise PermissionError(f'the token may not edit the dns records of zone {zone.get("name", self.zoneId)}')
+
+class SimulatedCloudflareApi(CloudflareApi):
+    # In-memory stand-in for Cloudflare, which only logs the changes
+    records = {}
+
+    def verifyToken(self):
+        pass
+
+    def listRecords(self, name, recordType):
+        if name not in self.records: # As if it already pointed to the primary (or was the A-record)
+            dyndns = name == self.config['dyndns']['dyndns_target']
+            self.records[name] = DnsRecord('simulated-' + name, name, 'A' if dyndns else self.config['general']['record_type'], '0.0.0.0' if dyndns else self.config['primary']['cname'])
+        return [self.records[name]]
+
+    def updateRecord(self, recordId, data):
+        logger.info(f'[Simulation] Cloudflare {recordId}: {data}')
+        self.records[data['name']] = dataclasses.replace(self.records[data['name']], **data)
+        return self.records[data['name']]
+
+if args.simulate:
+    CloudflareApi = SimulatedCloudflareApi # Everything else stays the same
+
+def resolveNameToRecord(cloudflare, name, recordType, ours=lambda dns: False):
+    # Only records of the given type count - e.g. the zone apex used for MX also has A and TXT records
+    logger.debug(f'Resolving {name} ({recordType}) to a record-id...')
+    records = [dns for dns in cloudflare.listRecords(name, recordType) if dns.name == name and dns.type == recordType]
+    if len(records) > 1:
+        # E.g. several MX records: Only the one already managed by us may be switched
+        records = [dns for dns in records if ours(dns)]
+        if len(records) != 1:
+            raise ValueError(f'{name} has several {recordType} records and {"none" if len(records) == 0 else "more than one"} of them points to the primary or secondary target (or carries cloudflare.tags), so it is unclear which one to switch')
+    if len(records) == 0:
+        raise KeyError(f'{name} has no {recordType} record')
+    logger.debug(name + ' record-id is ' + records[0].id)
+    return records[0]
+
+# Defaults for everything not given in the config (e.g. TOML can't express null, so those keys are just omitted there)
+configDefaults = {
+    'cloudflare': {'zone_id': None, 'token': None, 'on_conflict': 'replace', 'audit_file': None, 'audit_max_bytes': 10485760, 'audit_backups': 5, 'requests_per_minute': 240, 'comment_reason': False, 'comment_max_length': 100, 'api_url': 'https://api.cloudflare.com/client/v4', 'client_cert': None, 'client_key': None, 'ca_file': None, 'tags': []},
+    'general': {'timeout': 10, 'dynamic_cname': None, 'record_type': 'CNAME', 'mx_priority': 10, 'update_interval': 30, 'external_resolver': 'default', 'force_ipv4_only': False, 'user_agent': None, 'request_id': False, 'max_latency': None, 'instance_id': None, 'instance_conflict_window': 900, 'crash_report_file': None, 'ttl_strategy': 'target', 'ttl_fixed': 60, 'ttl_stable_after': 3600},
+    'http': {'auth_token': None, 'auth_basic': None, 'auth_exempt_healthz': True, 'healthz_mode': 'process', 'healthz_cloudflare_failures': 3, 'tls_cert': None, 'tls_key': None, 'rate_limit': None, 'rate_limit_burst': 20, 'max_connections': 50, 'public_status': False, 'public_status_title': None},
+    'telegram': {'token': None, 'target': None},
+    'dyndns': {'dyndns_target': None, 'dyndns_ttl': 60, 'dyndns_hold_down': 0},
+    'primary': {'cname': None, 'subnets': [], 'ttl': 60, 'confidence': 4},
+    'secondary': {'cname': None, 'subnets': [], 'ttl': 300},
+    'vault': {'address': None, 'token': None, 'role_id': None, 'secret_id': None, 'approle_mount': 'approle', 'refresh_interval': 3600},
+    'chaos': {'enabled': False, 'resolver_failure_probability': 0, 'resolver_delay': 0, 'cloudflare_failure_probability': 0},
+    'history': {'size': 100, 'file': None, 'checks_size': 100, 'database': None, 'database_retention': 31536000},
+    'heartbeat': {'url': None, 'interval': 300, 'telegram': False},
+    'syslog': {'address': None, 'protocol': 'udp', 'facility': 'daemon', 'app_name': 'cloudflare-cname-switcher'},
+    'snmp': {'targets': [], 'community': 'public', 'enterprise_oid': '1.3.6.1.4.1.8072.9999.9999.1'},
+    'metrics': {'push_gateway': None, 'push_interval': 60, 'push_job': 'cloudflare_cname_switcher', 'push_grouping_key': {}},
+    'watchdog': {'grace': 300, 'exit': False},
+    'retry': {'attempts': 1, 'backoff': 'exponential', 'delay': 1, 'max_delay': 30, 'jitter': 0.1, 'retry_on': 'retryable'},
+    'pin': {'target': None, 'until': None},
+    'planned_failovers': {'schedule': [], 'notice': 900},
+    'propagation': {'resolvers': [], 'interval': 5, 'deadline': 600}
+}
+
+durationUnits = {'ms': 0.001, 's': 1, 'm': 60, 'h': 3600, 'd': 86400}
+
+def parseDuration(value):
+    # Durations are given in seconds or as strings like '90s', '5m' or '1h30m'
+    if not isinstance(value, str):
+        return value
+    if re.fullmatch(r'\s*\d+(\.\d+)?\s*', value):
+        seconds = float(value)
+    elif re.fullmatch(r'(\s*\d+(\.\d+)?\s*(ms|s|m|h|d))+\s*', value):
+        seconds = sum(float(amount) * durationUnits[unit] for amount, unit in re.findall(r'(\d+(?:\.\d+)?)\s*(ms|s|m|h|d)', value))
+    else:
+        raise ValueError(f'{value!r} is not a valid duration (e.g. 90, \'90s\', \'5m\' or \'1h30m\')')
+    return int(seconds) if seconds.is_integer() else seconds
+
+def parseTimestamp(value):
+    # ISO timestamps (UTC if without offset) - YAML and TOML already turn unquoted ones into datetimes
+    if not isinstance(value, datetime.datetime):
+        value = datetime.datetime.fromisoformat(value)
+    return (value if value.tzinfo is not None else value.replace(tzinfo=datetime.timezone.utc)).astimezone(datetime.timezone.utc)
+
+def checkPositive(value):
+    return None if value > 0 else 'should be greater than 0'
+
+def checkOptionalPositive(value):
+    return None if value is None else checkPositive(value)
+
+def checkDuration(value):
+    try:
+        return checkPositive(parseDuration(value))
+    except ValueError as e:
+        return str(e)
+
+def checkDisabledOrDuration(value):
+    return None if value is None else checkDuration(value)
+
+def checkOptionalDuration(value):
+    try:
+        return None if parseDuration(value) >= 0 else 'should not be negative'
+    except ValueError as e:
+        return str(e)
+
+def checkProbability(value):
+    return None if 0 <= value <= 1 else 'should be between 0 and 1'
+
+def checkTtl(value):
+    try:
+        value = parseDuration(value)
+    except ValueError as e:
+        return str(e)
+    return None if value == 1 or (isinstance(value, int) and 30 <= value <= 86400) else 'should be 1 (automatic) or between 30 and 86400 whole seconds'
+
+def checkExternalResolver(value):
+    if value.startswith('interface:') and not re.fullmatch(r'interface:[^\s/:]{1,15}', value):
+        return 'should name the network interface after interface: (e.g. interface:wan0)'
+    return None
+
+def checkTtlStrategy(value):
+    return None if value in ('target', 'fixed', 'min', 'primary', 'dynamic') else 'should be target, fixed, min, primary or dynamic'
+
+def checkHealthzMode(value):
+    return None if value in ('process', 'ingress') else 'should be process or ingress'
+
+def checkRecordType(value):
+    return None if value in ('CNAME', 'MX') else 'should be CNAME or MX'
+
+def checkMxPriority(value):
+    return None if 0 <= value <= 65535 else 'should be between 0 and 65535'
+
+def checkInstanceId(value):
+    return None if value is None or re.fullmatch(r'[\w.-]{1,40}', value) else 'should be up to 40 letters, digits, dots, dashes or underscores'
+
+def checkHttpUrl(value):
+    return None if re.match(r'https?://', value) else 'should start with http:// or https://'
+
+def checkResolvers(value):
+    for i, resolver in enumerate(value):
+        if not isinstance(resolver, str) or checkHttpUrl(resolver) is not None:
+            return f'entry {i} should be the URL of a DNS-over-HTTPS JSON API'
+    return None
+
+def checkConflictPolicy(value):
+    return None if value in ('fail', 'replace') else 'should be fail or replace'
+
+def checkBackoff(value):
+    return None if value in ('constant', 'exponential') else 'should be constant or exponential'
+
+def checkPinTarget(value):
+    return None if value in (None, 'primary', 'secondary') else 'should be primary or secondary'
+
+def checkOptionalTimestamp(value):
+    try:
+        if value is not None:
+            parseTimestamp(value)
+        return None
+    except ValueError as e:
+        return str(e)
+
+def checkPlannedFailovers(value):
+    # A list of {at, target, duration}, e.g. {at: 2024-07-01T02:00:00Z, target: secondary, duration: 2h}
+    for i, entry in enumerate(value):
+        if not isinstance(entry, dict) or set(entry) != {'at', 'target', 'duration'}:
+            return f'entry {i} should have exactly an at, target and duration'
+        if not isinstance(entry['at'], (str, datetime.datetime)) or checkOptionalTimestamp(entry['at']) is not None:
+            return f'entry {i}: at should be an ISO timestamp'
+        if entry['target'] is None or checkPinTarget(entry['target']) is not None:
+            return f'entry {i}: target should be primary or secondary'
+        if not isinstance(entry['duration'], (int, float, str)) or checkDuration(entry['duration']) is not None:
+            return f'entry {i}: duration should be a positive duration'
+    return None
+
+def checkRetryOn(value):
+    return None if value in ('retryable', 'any') else 'should be retryable or any'
+
+def checkTelegramTargets(value):
+    # A chat id, or a list of chat ids and/or {chat_id, message_thread_id} to also post into a forum topic
+    for i, target in enumerate(value if isinstance(value, list) else []):
+        if isinstance(target, dict):
+            if not isinstance(target.get('chat_id'), (str, int)) or not set(target) <= {'chat_id', 'message_thread_id'}:
+                return f'entry {i} should have a chat_id and optionally a message_thread_id'
+        elif not isinstance(target, (str, int)):
+            return f'entry {i} should be a chat id'
+    return None
+
+syslogFacilities = {'kern': 0, 'user': 1, 'daemon': 3, 'local0': 16, 'local1': 17, 'local2': 18, 'local3': 19, 'local4': 20, 'local5': 21, 'local6': 22, 'local7': 23}
+
+def checkSyslogProtocol(value):
+    return None if value in ('udp', 'tcp', 'tls') else 'should be udp, tcp or tls'
+
+def checkSyslogFacility(value):
+    return None if value in syslogFacilities else 'should be one of ' + ', '.join(syslogFacilities)
+
+def checkSnmpTargets(value):
+    for i, target in enumerate(value):
+        if not isinstance(target, str) or not re.fullmatch(r'(\[[0-9a-fA-F:.]+\]|[^:\[\]]+)(:\d+)?', target):
+            return f'entry {i} should be given as host, host:port or [ipv6]:port'
+    return None
+
+def checkRecordTags(value):
+    for i, tag in enumerate(value):
+        if not isinstance(tag, str) or not re.fullmatch(r'[^:\s]+:\S*', tag):
+            return f'entry {i} should be given as name:value'
+    return None
+
+def checkOid(value):
+    return None if re.fullmatch(r'\d+(\.\d+)+', value) else 'should be a numeric OID like 1.3.6.1.4.1.8072.9999.9999.1'
+
+def checkSubnets(value):
+    for i, subnet in enumerate(value):
+        try:
+            ipaddress.ip_network(subnet)
+        except (TypeError, ValueError) as e:
+            return f'entry {i} is not a valid subnet ({e})'
+    return None
+
+# Every known setting as (allowed types, check returning a problem or None)
+configSchema = {
+    'cloudflare': {
+        'zone_id': ((str, type(None)), None),
+        'token': ((str, type(None)), None),
+        'on_conflict': ((str,), checkConflictPolicy),
+        'audit_file': ((str, type(None)), None),
+        'audit_max_bytes': ((int,), checkPositive),
+        'audit_backups': ((int,), checkPositive),
+        'requests_per_minute': ((int, float), checkPositive),
+        'comment_reason': ((bool,), None),
+        'comment_max_length': ((int,), checkPositive),
+        'api_url': ((str,), checkHttpUrl),
+        'client_cert': ((str, type(None)), None),
+        'client_key': ((str, type(None)), None),
+        'ca_file': ((str, type(None)), None),
+        'tags': ((list,), checkRecordTags)
+    },
+    'general': {
+        'timeout': ((int, float, str), checkDuration),
+        'dynamic_cname': ((str, type(None)), None),
+        'record_type': ((str,), checkRecordType),
+        'mx_priority': ((int,), checkMxPriority),
+        'update_interval': ((int, float, str), checkDuration),
+        'external_resolver': ((str,), checkExternalResolver),
+        'force_ipv4_only': ((bool,), None),
+        'user_agent': ((str, type(None)), None),
+        'request_id': ((bool,), None),
+        'max_latency': ((int, float, str, type(None)), checkDisabledOrDuration),
+        'instance_id': ((str, type(None)), checkInstanceId),
+        'instance_conflict_window': ((int, float, str, type(None)), checkDisabledOrDuration),
+        'crash_report_file': ((str, type(None)), None),
+        'ttl_strategy': ((str,), checkTtlStrategy),
+        'ttl_fixed': ((int, str), checkTtl),
+        'ttl_stable_after': ((int, float, str), checkDuration)
+    },
+    'http': {
+        'auth_token': ((str, type(None)), None),
+        'auth_basic': ((str, type(None)), None),
+        'auth_exempt_healthz': ((bool,), None),
+        'healthz_mode': ((str,), checkHealthzMode),
+        'healthz_cloudflare_failures': ((int,), checkPositive),
+        'rate_limit': ((int, float, type(None)), checkOptionalPositive),
+        'rate_limit_burst': ((int,), checkPositive),
+        'max_connections': ((int,), checkPositive),
+        'public_status': ((bool,), None),
+        'public_status_title': ((str, type(None)), None),
+        'tls_cert': ((str, type(None)), None),
+        'tls_key': ((str, type(None)), None)
+    },
+    'telegram': {
+        'token': ((str, type(None)), None),
+        'target': ((str, int, list, type(None)), checkTelegramTargets)
+    },
+    'dyndns': {
+        'dyndns_target': ((str, type(None)), None),
+        'dyndns_ttl': ((int, str), checkTtl),
+        'dyndns_hold_down': ((int, float, str), checkOptionalDuration)
+    },
+    'primary': {
+        'cname': ((str, type(None)), None),
+        'subnets': ((list,), checkSubnets),
+        'ttl': ((int, str), checkTtl),
+        'confidence': ((int,), checkPositive)
+    },
+    'secondary': {
+        'cname': ((str, type(None)), None),
+        'subnets': ((list,), checkSubnets),
+        'ttl': ((int, str), checkTtl)
+    },
+    'vault': {
+        'address': ((str, type(None)), None),
+        'token': ((str, type(None)), None),
+        'role_id': ((str, type(None)), None),
+        'secret_id': ((str, type(None)), None),
+        'approle_mount': ((str,), None),
+        'refresh_interval': ((int, float, str), checkDuration)
+    },
+    'chaos': {
+        'enabled': ((bool,), None),
+        'resolver_failure_probability': ((int, float), checkProbability),
+        'resolver_delay': ((int, float, str), checkOptionalDuration),
+        'cloudflare_failure_probability': ((int, float), checkProbability)
+    },
+    'history': {
+        'size': ((int,), checkPositive),
+        'file': ((str, type(None)), None),
+        'checks_size': ((int,), checkPositive),
+        'database': ((str, type(None)), None),
+        'database_retention': ((int, float, str, type(None)), checkDisabledOrDuration)
+    },
+    'heartbeat': {
+        'url': ((str, type(None)), None),
+        'interval': ((int, float, str), checkDuration),
+        'telegram': ((bool,), None)
+    },
+    'syslog': {
+        'address': ((str, type(None)), None),
+        'protocol': ((str,), checkSyslogProtocol),
+        'facility': ((str,), checkSyslogFacility),
+        'app_name': ((str,), None)
+    },
+    'snmp': {
+        'targets': ((list,), checkSnmpTargets),
+        'community': ((str,), None),
+        'enterprise_oid': ((str,), checkOid)
+    },
+    'metrics': {
+        'push_gateway': ((str, type(None)), None),
+        'push_interval': ((int, float, str), checkDuration),
+        'push_job': ((str,), None),
+        'push_grouping_key': ((dict,), None)
+    },
+    'watchdog': {
+        'grace': ((int, float, str), checkDuration),
+        'exit': ((bool,), None)
+    },
+    'retry': {
+        'attempts': ((int,), checkPositive),
+        'backoff': ((str,), checkBackoff),
+        'delay': ((int, float, str), checkDuration),
+        'max_delay': ((int, float, str), checkDuration),
+        'jitter': ((int, float), checkProbability),
+        'retry_on': ((str,), checkRetryOn)
+    },
+    'pin': {
+        'target': ((str, type(None)), checkPinTarget),
+        'until': ((str, datetime.datetime, type(None)), checkOptionalTimestamp)
+    },
+    'planned_failovers': {
+        'schedule': ((list,), checkPlannedFailovers),
+        'notice': ((int, float, str, type(None)), checkDisabledOrDuration)
+    },
+    'propagation': {
+        'resolvers': ((list,), checkResolvers),
+        'interval': ((int, float, str), checkDuration),
+        'deadline': ((int, float, str), checkDuration)
+    }
+}
+
+# These settings are converted into seconds after the validation
+durationSettings = [('general', 'timeout'), ('general', 'update_interval'), ('general', 'max_latency'), ('general', 'instance_conflict_window'), ('dyndns', 'dyndns_ttl'), ('dyndns', 'dyndns_hold_down'), ('general', 'ttl_fixed'), ('general', 'ttl_stable_after'), ('primary', 'ttl'), ('secondary', 'ttl'), ('vault', 'refresh_interval'), ('chaos', 'resolver_delay'), ('heartbeat', 'interval'), ('metrics', 'push_interval'), ('retry', 'delay'), ('retry', 'max_delay'), ('watchdog', 'grace'), ('history', 'database_retention'), ('planned_failovers', 'notice'), ('propagation', 'interval'), ('propagation', 'deadline')]
+
+def validateConfig(config, schema, path=''):
+    # Returns a list of all problems, each prefixed with the path of the setting
+    problems = []
+    for key, value in config.items():
+        keyPath = path + str(key)
+        if key not in schema:
+            suggestions = difflib.get_close_matches(str(key), schema.keys(), n=1)
+            problems.append(f'{keyPath}: unknown setting' + (f' (did you mean {path}{suggestions[0]}?)' if suggestions else ''))
+        elif isinstance(schema[key], dict):
+            if isinstance(value, dict):
+                problems += validateConfig(value, schema[key], keyPath + '.')
+            else:
+                problems.append(f'{keyPath}: should be a section, but is {type(value).__name__}')
+        else:
+            types, check = schema[key]
+            if not isinstance(value, types) or (isinstance(value, bool) and bool not in types):
+                problems.append(f'{keyPath}: should be of type {"/".join(t.__name__ for t in types)}, but is {type(value).__name__}')
+            elif check is not None and check(value) is not None:
+                problems.append(f'{keyPath}: {check(value)}')
+    return problems
+
+def mergeConfig(base, overlay):
+    # Deep-merges overlay into base: Dicts are merged, everything else (e.g. lists) is replaced
+    for key, value in overlay.items():
+        if isinstance(value, dict) and isinstance(base.get(key), dict):
+            mergeConfig(base[key], value)
+        else:
+            base[key] = value
+    return base
+
+profilesApplied = set() # Files with a document of the selected profile, see readProfiledConfig()
+configFilesRead = set() # Files the config consists of (including the profile overlay, even if missing), see watchConfigFile()
+configIncludeGlobs = set() # Include patterns like conf.d/*.yml, whose matches may change later, see watchConfigFile()
+
+def readConfigFile(path, included=()):
+    logger.debug(f'Reading config file {path}...')
+    configFilesRead.add(path)
+    extension = os.path.splitext(path)[1].lower()
+    with open(path, 'rb') as configFile:
+        try:
+            if extension == '.toml':
+                config = tomllib.load(configFile)
+            elif extension == '.json':
+                config = json.load(configFile)
+            else:
+                config = {}
+                for document in yaml.safe_load_all(configFile):
+                    # Further documents (separated by ---) may be limited to one profile, e.g. to only differ in tokens and records on staging
+                    document = document or {}
+                    assert isinstance(document, dict), f'every document of {path} should be a mapping'
+                    profile = document.pop('profile', None)
+                    if profile is not None and profile == args.profile:
+                        profilesApplied.add(path)
+                    if profile is None or profile == args.profile:
+                        mergeConfig(config, document)
+        except (tomllib.TOMLDecodeError, json.JSONDecodeError) as e:
+            raise ValueError(f'Could not parse {path}: {e}') from e # These errors do not mention the file on their own
+    # Merge all included files (relative to this file) on top of this one
+    patterns = config.pop('include', None) or []
+    assert isinstance(patterns, list), f'include in {path} should be a list'
+    included = included + (os.path.realpath(path),)
+    for pattern in patterns:
+        files = sorted(glob.glob(os.path.join(os.path.dirname(path), pattern)))
+        if glob.has_magic(pattern):
+            configIncludeGlobs.add(os.path.join(os.path.dirname(path), pattern))
+        elif len(files) == 0:
+            raise FileNotFoundError(f'{pattern} (included by {path}) does not exist')
+        for file in files:
+            assert os.path.realpath(file) not in included, f'{file} is included recursively'
+            mergeConfig(config, readConfigFile(file, included))
+    return config
+
+def readProfiledConfig(path):
+    # The config, with the overlay file of --profile (e.g. config.staging.yml for config.yml) merged on top
+    profilesApplied.clear()
+    configFilesRead.clear()
+    configIncludeGlobs.clear()
+    config = readConfigFile(path)
+    if args.profile:
+        root, extension = os.path.splitext(path)
+        overlay = f'{root}.{args.profile}{extension}'
+        configFilesRead.add(overlay) # Also reload once it is created
+        if os.path.exists(overlay):
+            mergeConfig(config, readConfigFile(overlay))
+        elif len(profilesApplied) == 0:
+            raise ValueError(f'Profile {args.profile} is neither a document of {path} (or its includes) nor given as {overlay}') # Most likely a typo
+    return config
+
+def vaultRequest(config, method, path, token=None, data=None):
+    address = config['vault']['address'] or os.environ.get('VAULT_ADDR')
+    assert address, 'vault.address (or VAULT_ADDR) should be given to resolve secret:// references'
+    def send():
+        with openUrl(config, method, address.rstrip('/') + '/v1/' + path.lstrip('/'), data, {} if token is None else {'X-Vault-Token': token}) as response:
+            return json.load(response)
+    return withRetries(config, f'Vault {method} {path}', send)
+
+def vaultLogin(config):
+    token = config['vault']['token'] or os.environ.get('VAULT_TOKEN')
+    if token:
+        return token
+    roleId = config['vault']['role_id'] or os.environ.get('VAULT_ROLE_ID')
+    secretId = config['vault']['secret_id'] or os.environ.get('VAULT_SECRET_ID')
+    assert roleId and secretId, 'vault.token or vault.role_id and vault.secret_id should be given to resolve secret:// references'
+    return vaultRequest(config, 'POST', 'auth/' + config['vault']['approle_mount'] + '/login', data={'role_id': roleId, 'secret_id': secretId})['auth']['client_token']
+
+def resolveSecrets(config, references):
+    # Replaces the given config values by the secret their 'secret://vault/path#key' reference points to
+    if len(references) == 0:
+        return
+    token = vaultLogin(config)
+    for section, key, reference in references:
+        match = re.fullmatch(r'secret://vault/([^#]+)#(.+)', reference)
+        if match is None:
+            raise ValueError(f'{section}.{key}: {reference} should look like secret://vault/path#key')
+        data = vaultRequest(config, 'GET', match.group(1), token)['data']
+        if isinstance(data.get('data'), dict) and 'metadata' in data:
+            data = data['data'] # KV version 2 wraps the secret once more
+        if match.group(2) not in data:
+            raise KeyError(f'{section}.{key}: {reference} does not exist')
+        config[section][key] = data[match.group(2)]
+
+cloudflareTokenCheck = None # Result of the most recent token verification (done on every (re)load)
+recordTakeovers = {} # Record name -> conflict, reported once the record got replaced by us
+
+def managedByTags(config, record):
+    # Tags are more robust than the comment, which people edit by hand
+    return len(config['cloudflare']['tags']) > 0 and set(config['cloudflare']['tags']) <= set(record.tags)
+
+def managedDynamicRecord(config, record):
+    # With our tags it is ours, whatever it points to (e.g. after the primary.cname was changed)
+    return managedByTags(config, record) or record.content.rstrip('.').lower() in (config['primary']['cname'].lower(), config['secondary']['cname'].lower())
+
+def loadConfig(path):
+    # Loads and validates the config, including the Cloudflare record-ids - raises on any problem
+    logger.debug('Loading config...')
+    config = mergeConfig(copy.deepcopy(configDefaults), readProfiledConfig(path))
+    problems = validateConfig(config, configSchema)
+    if len(problems):
+        raise ValueError('Invalid config:\n' + '\n'.join(problems))
+    for section, key in durationSettings:
+        config[section][key] = parseDuration(config[section][key])
+    secretReferences = [(section, key, value) for section, settings in config.items() for key, value in settings.items() if isinstance(value, str) and value.startswith('secret://')]
+    resolveSecrets(config, secretReferences)
+
+    # Stuff, which should be set, when the user is not using the sample-config anymore...
+    assert config['cloudflare']['zone_id'], 'cloudflare.zone_id should be given'
+    assert config['cloudflare']['token'], 'cloudflare.token should be given'
+    assert config['general']['dynamic_cname'], 'general.dynamic_cname should be given'
+    assert config['primary']['cname'], 'primary.cname should be given'
+    assert config['secondary']['cname'], 'secondary.cname should be given'
+    assert len(config['primary']['subnets']) > 0 or len(config['secondary']['subnets']) > 0, 'primary or secondary subnets should be given'
+    if config['telegram']['token'] is not None:
+        assert config['telegram']['target'], 'telegram.target should be given'
+    if config['http']['auth_basic'] is not None:
+        assert ':' in config['http']['auth_basic'], 'http.auth_basic should be given as user:password'
+    if config['http']['tls_cert'] is not None:
+        assert config['http']['tls_key'], 'http.tls_key should be given'
+
+    global cloudflareTokenCheck
+    try:
+        CloudflareApi(config).verifyToken()
+        cloudflareTokenCheck = {'time': datetime.datetime.now(datetime.timezone.utc).isoformat(), 'success': True, 'error': None}
+    except Exception as e:
+        cloudflareTokenCheck = {'time': datetime.datetime.now(datetime.timezone.utc).isoformat(), 'success': False, 'error': str(e)}
+        raise ValueError(f'Cloudflare token check failed: {e}') from e
+
+    # Resolve the dynamic_cname (and the dyndns_target) to a dns entry id of Cloudflare
+    conflicts = {} # Records, which look like they are not managed by us (yet)
+    try:
+        dnsRecord = resolveNameToRecord(CloudflareApi(config), config['general']['dynamic_cname'], config['general']['record_type'], lambda dns: managedDynamicRecord(config, dns))
+    except Exception as e:
+        raise ValueError('Could not resolve ' + config['general']['dynamic_cname'] + ' to a Cloudflare dns id!') from e
+    if not managedDynamicRecord(config, dnsRecord):
+        conflicts[dnsRecord.name] = f'{dnsRecord.name} is a {dnsRecord.type} record pointing to {dnsRecord.content}, which is neither the primary nor the secondary target'
+    dynDnsRecord = None
+    if config['dyndns']['dyndns_target']:
+        try:
+            dynDnsRecord = resolveNameToRecord(CloudflareApi(config), config['dyndns']['dyndns_target'], 'A')
+        except Exception as e:
+            raise ValueError('Could not resolve ' + config['dyndns']['dyndns_target'] + ' to a Cloudflare dns id!') from e
+    if len(conflicts) and config['cloudflare']['on_conflict'] == 'fail':
+        raise ValueError('Refusing to take over records (see cloudflare.on_conflict):\n' + '\n'.join(conflicts.values()))
+    return config, dnsRecord.id, None if dynDnsRecord is None else dynDnsRecord.id, secretReferences, conflicts
+
+def loadShadowConfig(path):
+    # Only the election matters for the shadow, so neither secrets nor Cloudflare are touched
+    config = mergeConfig(copy.deepcopy(configDefaults), readConfigFile(path))
+    problems = validateConfig(config, configSchema)
+    if len(problems):
+        raise ValueError('Invalid shadow config:\n' + '\n'.join(problems))
+    assert len(config['primary']['subnets']) > 0 or len(config['secondary']['subnets']) > 0, 'primary or secondary subnets should be given'
+    return config
+
+def parseSubnets(config):
+    # The (primary, secondary) subnets the election of this config tells the uplinks apart by
+    return [ipaddress.ip_network(n) for n in config['primary']['subnets']], [ipaddress.ip_network(n) for n in config['secondary']['subnets']]
+
+def applyConfig(newConfig, dnsRecordId, dynDnsRecordId, newSecretReferences, conflicts=None):
+    global recordTakeovers, config, CloudflareDnsRecordId, CloudflareDynDnsRecordId, configSubnets, primarySubnets, secondarySubnets, primarySubnetsGiven, secondarySubnetsGiven
+    global telegramToken, telegramTargets, httpAuthToken, httpAuthBasic, httpAuthExemptHealthz, httpHealthzMode, httpHealthzCloudflareFailures, httpTlsCert, httpTlsKey, loopTime
+    global httpRateLimit, httpRateLimitBurst, httpMaxConnections, httpPublicStatus, httpPublicStatusTitle
+    global secretReferences, secretsRefreshAt, cloudflare, chaosSettings, decisionHistory, checkHistory
+    config = newConfig
+    if decisionHistory.maxlen != config['history']['size']:
+        decisionHistory = collections.deque(decisionHistory, maxlen=config['history']['size'])
+    if checkHistory.maxlen != config['history']['checks_size']:
+        checkHistory = collections.deque(checkHistory, maxlen=config['history']['checks_size'])
+    chaosSettings = dict(config['chaos'])
+    if chaosSettings['enabled']:
+        logger.warning(f'Chaos injection is enabled: {chaosSettings}')
+    cloudflare = CloudflareApi(config)
+    CloudflareDnsRecordId = dnsRecordId
+    CloudflareDynDnsRecordId = dynDnsRecordId
+    secretReferences = newSecretReferences
+    if conflicts is not None: # Not re-checked e.g. by a secrets refresh
+        recordTakeovers = dict(conflicts)
+    secretsRefreshAt = time.monotonic() + config['vault']['refresh_interval']
+
+    # Load config-elements
+    configSubnets = parseSubnets(config)
+    primarySubnets, secondarySubnets = configSubnets
+    primarySubnetsGiven = len(primarySubnets) > 0
+    secondarySubnetsGiven = len(secondarySubnets) > 0
+    telegramToken = config['telegram']['token']
+    telegramTarget = config['telegram']['target']
+    telegramTargets = [t if isinstance(t, dict) else {'chat_id': t} for t in (telegramTarget if isinstance(telegramTarget, list) else [telegramTarget])]
+    httpAuthToken = config['http']['auth_token']
+    httpAuthBasic = config['http']['auth_basic']
+    httpAuthExemptHealthz = config['http']['auth_exempt_healthz']
+    httpHealthzMode = config['http']['healthz_mode']
+    httpHealthzCloudflareFailures = config['http']['healthz_cloudflare_failures']
+    httpTlsCert = config['http']['tls_cert']
+    httpTlsKey = config['http']['tls_key']
+    httpRateLimit = config['http']['rate_limit']
+    httpRateLimitBurst = config['http']['rate_limit_burst']
+    httpMaxConnections = config['http']['max_connections']
+    httpPublicStatus = config['http']['public_status']
+    httpPublicStatusTitle = config['http']['public_status_title']
+    loopTime = config['general']['update_interval']
+    getter.timeout = config['general']['timeout']
+    if config['general']['force_ipv4_only']:
+        import urllib3
+        urllib3.util.connection.HAS_IPV6 = False
+
+# Configure the ipgetter
+getter = IPGetter()
+
+def interfaceAddress(name):
+    # For routers running this on the edge device: The IPv4 address of their uplink interface (Linux only)
+    import fcntl # Not available on Windows
+    try:
+        with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+            address = ipaddress.ip_address(fcntl.ioctl(sock.fileno(), 0x8915, struct.pack('256s', name.encode('utf8')))[20:24]) # SIOCGIFADDR
+    except OSError as e:
+        raise ConnectionError(f'Could not read the IPv4 address of {name}: {e.strerror}') from e
+    if not address.is_global:
+        raise ValueError(f'{name} only has the non-public address {address} (e.g. behind a NAT of the provider)')
+    return address
+
+def configChanged(oldConfig, newConfig, *paths):
+    # Did any of the given (dot-separated) config paths change?
+    for path in paths:
+        oldValue, newValue = oldConfig, newConfig
+        for key in path.split('.'):
+            oldValue = (oldValue or {}).get(key)
+            newValue = (newValue or {}).get(key)
+        if oldValue != newValue:
+            return True
+    return False
 
-# Load config-elements
-primaryConfidence = int(config['primary']['confidence'] / 2)
-primarySubnets = [ipaddress.ip_network(n) for n in config['primary']['subnets']]
-secondarySubnets = [ipaddress.ip_network(n) for n in config['secondary']['subnets']]
-primarySubnetsGiven = len(primarySubnets) > 0
-secondarySubnetsGiven = len(secondarySubnets) > 0
-telegramToken = config['telegram']['token']
-telegramTarget = config['telegram']['target']
-if telegramToken is not None:
-    assert telegramTarget, 'telegram.target should be given'
-if config['general']['force_ipv4_only']:
-    import urllib3
-    urllib3.util.connection.HAS_IPV6 = False
-
-def resolveNameToRecordId(config, name):
-    logger.debug(f'Resolving {name} to a record-id...')
-    request = Request(
-        'https://api.cloudflare.com/client/v4/zones/' + config['cloudflare']['zone_id'] + '/dns_records?name=' + name,
-        method='GET',
-        headers={
-            'Authorization': 'Bearer ' + config['cloudflare']['token'],
-            'Content-Type': 'application/json'
-            }
-    )
-    for dns in json.load(urlopen(request, timeout=config['general']['timeout']))['result']:
-        if dns['name'] == name:
-            logger.debug(name + ' record-id is ' + dns['id'])
-            return dns['id']
-    raise KeyError(name) # record with that name not found
-
-# Resolve the dynamic_cname to a dns entry id of Cloudflare
 try:
-    CloudflareDnsRecordId = resolveNameToRecordId(config, config['general']['dynamic_cname'])
-except:
-    logger.exception('Could not resolve ' + config['general']['dynamic_cname'] + ' to a Cloudflare dns id!')
+    applyConfig(*loadConfig(args.config))
+except Exception:
+    logger.exception('Could not load the config!')
     sys.exit(1)
-CloudflareDynDnsRecordId = None
-if config['dyndns']['dyndns_target']:
+primaryConfidence = int(config['primary']['confidence'] / 2)
+reloadRequested = False # Set by SIGHUP, /admin/reload and --watch_config
+reloadWaiters = [] # /admin/reload requests waiting for the outcome of the reload by the main loop
+reloadWaitersLock = threading.Lock()
+reconcileRequested = False # Set by SIGUSR2
+
+taskProgress = {} # Task -> (time of its last progress, monotonic deadline for the next one or None), see watchdog()
+wedgedTasks = set()
+
+def reportProgress(task, nextWithin=None):
+    # nextWithin: Seconds until the task reports progress again (plus watchdog.grace), None while it waits for work
+    taskProgress[task] = (time.time(), None if nextWithin is None else time.monotonic() + nextWithin + config['watchdog']['grace'])
+
+def supervised(task, target):
+    # Restarts a crashed background task with backoff, so e.g. a bug in the heartbeat does not end it silently for good
+    def run():
+        failures = 0
+        while True:
+            started = time.monotonic()
+            try:
+                target()
+                return # Finished regularly (e.g. on shutdown)
+            except Exception as e:
+                failures = 1 if time.monotonic() - started > 600 else failures + 1
+                delay = min(5 * 2 ** (failures - 1), 600)
+                logger.exception(f'The {task} task crashed, restarting it in {delay}s...')
+                recordEvent('health', f'The {task} task crashed ({e}), restarting it in {delay}s')
+                if task != 'telegram_sender':
+                    sendTelegramNotification(f'The {task} task crashed ({e}), restarting it in {delay}s.', False, ignorable=False)
+            if shutdownRequested.wait(delay):
+                return
+    return run
+
+def setFromSignalHandler(*events):
+    # Setting an event directly could deadlock, in case the signal interrupted the main thread inside of its wait()
+    def setEvents():
+        for event in events:
+            event.set()
+    threading.Thread(target=setEvents, daemon=True).start()
+
+# Runtime state (also read by the healthcheck/metric endpoint)
+oldExternalIPv4 = None
+externalIPv4 = None
+dyndnsCandidate = None # (IP, since) which differs from the published A-record, but is held down yet
+primaryActive = None
+externalIpOwner = None # Whose subnet contains the external IP? (primary, secondary, none or error)
+publishedCnameTtl = None # TTL of the most recent successful update of the dynamic_cname
+ignoreFirstNotification = True
+telegramQueue = queue.Queue() # Notifications to be sent by the telegramSender thread (None stops it)
+notificationBuffer = [] # Notifications taken from the telegramQueue, but not sent yet (e.g. because Telegram is unreachable)
+recentEvents = collections.deque(maxlen=50) # Most recent events, shown on the dashboard
+lastCloudflareUpdate = None # Result of the most recent Cloudflare record update
+lastCloudflareSuccess = None # When did a Cloudflare record update succeed the last time?
+cloudflareFailuresInRow = 0 # How many of the most recent Cloudflare record updates failed?
+lastResolveProblem = None # Why did the external IP resolution fail the last time?
+eventSubscribers = [] # Queues of all currently connected /events streams
+eventSubscribersLock = threading.Lock()
+uplinkStates = {} # Current streak of each uplink: healthy while the external IP is in its subnets
+lastUplinkAccounting = None
+
+def subnetOwner(ip, subnets):
+    # subnets are the (primary, secondary) ones of parseSubnets()
+    if True in [ip in n for n in subnets[0]]:
+        return 'primary'
+    if True in [ip in n for n in subnets[1]]:
+        return 'secondary'
+    return 'none'
+
+def nextPrimaryConfidence(confidence, owner, subnets):
+    # One step of the election: owner is the subnetOwner() of the external IP, or 'error' if it could not be resolved
+    if owner == 'primary' or (len(subnets[0]) == 0 and owner == 'none'):
+        return confidence + 1
+    if owner in ('secondary', 'error') or (len(subnets[1]) == 0 and owner == 'none'):
+        return 0
+    return confidence # In neither subnet -> ignored
+
+def pinFromConfig(config):
+    if config['pin']['target'] is None:
+        return None
+    until = config['pin']['until']
+    return {'target': config['pin']['target'], 'until': None if until is None else parseTimestamp(until).isoformat(), 'source': 'config'}
+
+pinnedTarget = pinFromConfig(config) # {target, until, source} while the election is overridden, see activePin()
+
+def pinTarget(pin):
+    # The target of this pin, unless it expired (the main loop then removes it)
+    if pin is None or (pin['until'] is not None and datetime.datetime.fromisoformat(pin['until']) <= datetime.datetime.now(datetime.timezone.utc)):
+        return None
+    return pin['target']
+
+def activePin():
+    return pinTarget(pinnedTarget) # Read once, as the main loop may remove it meanwhile
+
+plannedFailoverPhases = {} # '<start> <target>' -> announced, started or finished
+
+def plannedFailovers():
+    # The schedule of the config with parsed times, ordered by their start
+    entries = []
+    for entry in config['planned_failovers']['schedule']:
+        start = parseTimestamp(entry['at'])
+        end = start + datetime.timedelta(seconds=parseDuration(entry['duration']))
+        entries.append({'key': f'{start.isoformat()} {entry["target"]}', 'target': entry['target'], 'start': start, 'end': end})
+    return sorted(entries, key=lambda entry: entry['start'])
+
+def advancePlannedFailovers():
+    # Announces and starts (as pin until their end) the planned failovers - returns the seconds until the next of these steps, or None
+    global pinnedTarget
+    now = datetime.datetime.now(datetime.timezone.utc)
+    notice = datetime.timedelta(seconds=config['planned_failovers']['notice'] or 0)
+    nextStep = None
+    for entry in plannedFailovers():
+        phase = plannedFailoverPhases.get(entry['key'])
+        if now >= entry['end']:
+            plannedFailoverPhases[entry['key']] = 'finished' # The expiry of its pin is reported by the main loop
+            continue
+        if now >= entry['start']:
+            if phase != 'started': # Once started, removing the pin via /admin/pin aborts it
+                pinnedTarget = {'target': entry['target'], 'until': entry['end'].isoformat(), 'source': 'planned'}
+                plannedFailoverPhases[entry['key']] = 'started'
+                logger.info(f'Planned failover to {entry["target"]} started (until {entry["end"].isoformat()}).')
+                recordEvent('election', f'Planned failover to {entry["target"]} started, reverting at {entry["end"].isoformat()}')
+                sendTelegramNotification(f'Planned failover to {entry["target"]} *STARTED*. Reverting at `{entry["end"].isoformat()}`.', True)
+            step = entry['end']
+        elif now >= entry['start'] - notice:
+            if phase is None:
+                plannedFailoverPhases[entry['key']] = 'announced'
+                recordEvent('election', f'Planned failover to {entry["target"]} at {entry["start"].isoformat()} until {entry["end"].isoformat()}')
+                sendTelegramNotification(f'Planned failover to {entry["target"]} *SCHEDULED* at `{entry["start"].isoformat()}` until `{entry["end"].isoformat()}`.', True)
+            step = entry['start']
+        else:
+            step = entry['start'] - notice
+        nextStep = min(nextStep, step) if nextStep is not None else step
+    return None if nextStep is None else (nextStep - now).total_seconds()
+
+def electedTarget(confidence, active, requiredConfidence):
+    # The target to switch the dynamic_cname to, or None to keep it - requiredConfidence is the primary.confidence of the config
+    pin = activePin()
+    if pin == 'secondary':
+        return 'secondary' if active != False else None
+    if pin == 'primary' and confidence > 0:
+        return 'primary' if active != True else None # Without waiting for the confidence, but only while the checks see the primary
+    if confidence >= requiredConfidence and active != True:
+        return 'primary'
+    if confidence == 0 and active != False:
+        return 'secondary'
+    return None
+
+def cnameTtl(config, target):
+    # The TTL published along with the target, see general.ttl_strategy
+    strategy = config['general']['ttl_strategy']
+    lowest = min(config['primary']['ttl'], config['secondary']['ttl'])
+    if strategy == 'fixed':
+        return config['general']['ttl_fixed']
+    if strategy == 'min':
+        return lowest
+    if strategy == 'primary':
+        return config['primary']['ttl']
+    if strategy == 'dynamic' and (len(uplinkStates) == 0 or min(time.monotonic() - state['since'] for state in uplinkStates.values()) < config['general']['ttl_stable_after']):
+        return lowest # The health changed recently (or is not known for long), so the next switch may be close
+    return config[target]['ttl']
+
+def dynamicCnameData(config, target):
+    data = {
+        'type': config['general']['record_type'],
+        'name': config['general']['dynamic_cname'],
+        'content': config[target]['cname'],
+        'ttl': cnameTtl(config, target),
+        'proxied': False
+    }
+    if data['type'] == 'MX':
+        data['priority'] = config['general']['mx_priority'] # The mail server to use is switched instead
+    return data
+
+syslogQueue = queue.Queue(maxsize=1000) # Events to be sent by the syslogSender thread
+syslogSeverities = {'health': 4, 'election': 5, 'config': 5, 'cloudflare': 6, 'notification': 6, 'shadow': 6} # warning, notice or info
+
+def formatSyslogMessage(event):
+    # RFC 5424 with the event kind as structured data
+    priority = syslogFacilities[config['syslog']['facility']] * 8 + syslogSeverities.get(event['kind'], 6)
+    kind = re.sub(r'(["\\\]])', r'\\\1', event['kind']) # Escaped as structured data parameter value
+    return f'<{priority}>1 {event["time"]} {socket.gethostname()} {config["syslog"]["app_name"]} {os.getpid()} {event["kind"]} [ccs@32473 kind="{kind}"] {event["message"]}'.encode('utf8')
+
+def syslogSender():
+    # Sends the events on its own thread, so an unreachable syslog server never delays the checks
+    connection = None
+    connectedTo = None
+    while True:
+        reportProgress('syslog_sender')
+        message = syslogQueue.get()
+        address, protocol = config['syslog']['address'], config['syslog']['protocol']
+        if address is None:
+            continue
+        reportProgress('syslog_sender', 0) # Busy now
+        host, port = address.rsplit(':', 1) if re.search(r':\d+$', address) else (address, '514' if protocol != 'tls' else '6514')
+        try:
+            if protocol == 'udp':
+                for family, _, _, _, sockaddr in socket.getaddrinfo(host.strip('[]'), int(port), type=socket.SOCK_DGRAM):
+                    with socket.socket(family, socket.SOCK_DGRAM) as syslogSocket:
+                        syslogSocket.sendto(message, sockaddr)
+                    break
+                continue
+            if connection is None or connectedTo != (address, protocol):
+                if connection is not None:
+                    connection.close()
+                connection = socket.create_connection((host.strip('[]'), int(port)), timeout=config['general']['timeout'])
+                if protocol == 'tls':
+                    connection = ssl.create_default_context().wrap_socket(connection, server_hostname=host.strip('[]'))
+                connectedTo = (address, protocol)
+            connection.sendall(str(len(message)).encode('ascii') + b' ' + message) # Octet counting (RFC 6587)
+        except OSError:
+            logger.exception(f'Could not send the event to syslog at {address}.')
+            if connection is not None:
+                connection.close()
+            connection = None # Reconnect with the next event (this one is lost)
+
+syslogThread = threading.Thread(target=supervised('syslog_sender', syslogSender))
+syslogThread.daemon = True
+syslogThread.start()
+
+historyDatabaseLock = threading.Lock()
+historyTables = {'events': ('kind', 'message'), 'decisions': ('decision',), 'uplinks': ('name', 'state')} # Besides the time of each row
+
+def openHistoryDatabase():
+    # Opened for every use, as it is used rarely and by several threads
+    connection = sqlite3.connect(config['history']['database'])
+    for table, columns in historyTables.items():
+        connection.execute(f'CREATE TABLE IF NOT EXISTS {table} (time TEXT NOT NULL, {", ".join(c + " TEXT" for c in columns)})')
+        connection.execute(f'CREATE INDEX IF NOT EXISTS {table}_time ON {table} (time)')
+    return connection
+
+def storeHistory(table, time, *values):
+    # Keeps events and decisions across restarts (and longer than Prometheus does), see history.database
+    if config['history']['database'] is None or args.simulate: # Simulated decisions would e.g. distort /reports/sla
+        return
     try:
-        CloudflareDynDnsRecordId = resolveNameToRecordId(config, config['dyndns']['dyndns_target'])
-    except:
-        logger.exception('Could not resolve ' + config['dyndns']['dyndns_target'] + ' to a Cloudflare dns id!')
-        sys.exit(2)
+        with historyDatabaseLock:
+            connection = openHistoryDatabase()
+            try:
+                with connection:
+                    connection.execute(f'INSERT INTO {table} VALUES (?{", ?" * len(values)})', (time,) + values)
+                    if config['history']['database_retention'] is not None:
+                        cutoff = datetime.datetime.now(datetime.timezone.utc) - datetime.timedelta(seconds=config['history']['database_retention'])
+                        connection.execute(f'DELETE FROM {table} WHERE time < ?', (cutoff.isoformat(),))
+            finally:
+                connection.close()
+    except sqlite3.Error:
+        logger.exception('Could not write to the history database.')
+
+def queryHistory(table, since=None, until=None, limit=10000):
+    # The most recent rows (as dicts, oldest first) between the ISO timestamps since and until
+    with historyDatabaseLock:
+        connection = openHistoryDatabase()
+        try:
+            rows = connection.execute(f'SELECT * FROM (SELECT time, {", ".join(historyTables[table])} FROM {table} WHERE time >= ? AND time <= ? ORDER BY time DESC LIMIT ?) ORDER BY time', (since or '', until or '~', limit)).fetchall()
+        finally:
+            connection.close()
+    return [dict(zip(('time',) + historyTables[table], row)) for row in rows]
+
+def recordEvent(kind, message):
+    event = {
+        'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+        'kind': kind,
+        'message': message
+    }
+    recentEvents.append(event)
+    storeHistory('events', event['time'], kind, message)
+    if config['syslog']['address'] is not None and not args.simulate:
+        try:
+            syslogQueue.put_nowait(formatSyslogMessage(event))
+        except queue.Full:
+            pass # The syslog server is too slow, so it will miss this event
+    with eventSubscribersLock:
+        for subscriber in eventSubscribers:
+            try:
+                subscriber.put_nowait(event)
+            except queue.Full:
+                pass # The client is not reading its stream, so it will miss this event
+
+def recordDecision(trigger, previousTarget, data, error):
+    # Audit log of every attempt to change the dynamic_cname, to reconstruct later why it happened - error is why it was not applied (or None)
+    decision = {
+        'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+        'trigger': trigger,
+        'external_ip': None if externalIPv4 is None or externalIpOwner == 'error' else str(externalIPv4), # On errors it is just the previous one
+        'external_ip_owner': externalIpOwner,
+        'primary_confidence': primaryConfidence,
+        'primary_confidence_required': config['primary']['confidence'],
+        'previous_target': previousTarget,
+        'target': currentTarget(),
+        'content': data['content'],
+        'ttl': data['ttl'],
+        'cloudflare_success': error is None,
+        'cloudflare_error': None if error is None else str(error) # E.g. also an InstanceConflictError, where Cloudflare was not even asked
+    }
+    decisionHistory.append(decision)
+    storeHistory('decisions', decision['time'], json.dumps(decision))
+    if config['history']['file'] is not None and not args.simulate:
+        try:
+            with open(config['history']['file'], 'a') as historyFile:
+                historyFile.write(json.dumps(decision) + '\n')
+        except OSError:
+            logger.exception('Could not append to the history file.')
+
+if config['history']['database'] is not None and not args.simulate:
+    # Continue where the previous run stopped
+    try:
+        decisionHistory.extend(json.loads(row['decision']) for row in queryHistory('decisions', limit=config['history']['size']))
+        recentEvents.extend(queryHistory('events', limit=recentEvents.maxlen))
+    except sqlite3.Error:
+        logger.exception('Could not read the history database.')
+
+class LatencyExceededError(Exception):
+    pass # The external IP was resolved, but too slow (see general.max_latency)
+
+def classifyResolveError(e):
+    # Machine-readable reason for dashboards, the exceptions differ between ipgetter2, urllib and the simulation
+    name = type(e).__name__
+    status = getattr(getattr(e, 'response', None), 'status_code', None) or getattr(e, 'code', None)
+    if isinstance(e, LatencyExceededError):
+        return 'latency', None
+    if isinstance(e, ssl.SSLError) or 'SSL' in name:
+        return 'tls_error', None
+    if isinstance(e, TimeoutError) or 'Timeout' in name:
+        return 'timeout', None
+    if isinstance(e, socket.gaierror):
+        return 'dns_error', None
+    if isinstance(status, int):
+        return 'status', status
+    if isinstance(e, ValueError):
+        return 'invalid_ip', None
+    if isinstance(e, OSError): # Also ConnectionError
+        return 'connection_error', None
+    return 'unknown', None
+
+def recordResolveProblem(e):
+    global lastResolveProblem
+    reason, status = classifyResolveError(e)
+    lastResolveProblem = {
+        'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+        'reason': reason,
+        'status': status,
+        'detail': str(e)
+    }
+    metricExternalIpLastFailureReason.info({'reason': reason, 'status': '' if status is None else str(status)})
+
+def recordCheck(duration, error=None):
+    # The pattern of the recent checks shows why a failover happened (see /history/checks)
+    checkHistory.append({
+        'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+        'duration': duration,
+        'external_ip': None if error is not None else str(externalIPv4),
+        'owner': externalIpOwner,
+        'reason': None if error is None else classifyResolveError(error)[0],
+        'error': None if error is None else str(error)
+    })
+
+def recordCloudflareUpdate(record, content, error=None):
+    global lastCloudflareUpdate, lastCloudflareSuccess, cloudflareFailuresInRow
+    lastCloudflareUpdate = {
+        'time': datetime.datetime.now(datetime.timezone.utc).isoformat(),
+        'record': record,
+        'content': content,
+        'success': error is None,
+        'error': None if error is None else str(error)
+    }
+    metricCloudflareLastAttemptResult.labels(record=record).set(1 if error is None else 0)
+    cloudflareFailuresInRow = 0 if error is None else cloudflareFailuresInRow + 1
+    if error is None:
+        lastCloudflareSuccess = lastCloudflareUpdate['time']
+        metricCloudflareLastSuccess.labels(record=record).set_to_current_time()
+        recordEvent('cloudflare', f'Updated {record} to {content}')
+        conflict = recordTakeovers.pop(record, None)
+        if conflict is not None:
+            logger.warning(f'Replaced a record not managed by us: {conflict}')
+            recordEvent('cloudflare', f'Replaced a record not managed by us: {conflict}')
+            sendTelegramNotification(f'Replaced a record not managed by this switcher: {conflict}', False, ignorable=False)
+    else:
+        recordEvent('cloudflare', f'Failed to update {record} to {content}: {error}')
+
+propagationChecks = {} # Record -> generation of its current check (every update of the record, even to the same content, ends the previous check)
+
+def checkPropagation(record, recordType, content):
+    # The API may accept a change, while the edge still serves stale data - so watch the public resolvers for it in the background
+    if len(config['propagation']['resolvers']) == 0:
+        return
+    generation = propagationChecks.get(record, 0) + 1
+    propagationChecks[record] = generation
+    threading.Thread(target=watchPropagation, args=(config, record, recordType, content, generation), daemon=True).start()
+
+def queryResolver(config, resolver, record, recordType):
+    # The JSON API of DNS-over-HTTPS (e.g. of Cloudflare and Google), returning the names/addresses in the answer
+    url = resolver + ('&' if '?' in resolver else '?') + urllib.parse.urlencode({'name': record, 'type': recordType})
+    with openUrl(config, 'GET', url, headers={'Accept': 'application/dns-json'}) as response:
+        return [str(answer.get('data', '')).split()[-1].rstrip('.').lower() for answer in json.load(response).get('Answer', []) if answer.get('data')] # MX data is 'priority name'
+
+def watchPropagation(config, record, recordType, content, generation):
+    started = time.monotonic()
+    pending = list(config['propagation']['resolvers'])
+    while propagationChecks.get(record) == generation and not shutdownRequested.is_set():
+        for resolver in list(pending):
+            try:
+                if content.rstrip('.').lower() in queryResolver(config, resolver, record, recordType):
+                    pending.remove(resolver)
+                    metricDnsPropagation.labels(record=record, resolver=resolver).set(time.monotonic() - started)
+            except Exception as e:
+                logger.debug(f'Could not query {resolver} for {record}: {e}')
+        if len(pending) == 0:
+            logger.info(f'{record} propagated as {content} after {time.monotonic() - started:.0f}s.')
+            return
+        if time.monotonic() - started >= config['propagation']['deadline']:
+            logger.warning(f'{record} is still not {content} at {", ".join(pending)} after {config["propagation"]["deadline"]}s.')
+            recordEvent('cloudflare', f'{record} is still not {content} at {", ".join(pending)} after {config["propagation"]["deadline"]}s')
+            sendTelegramNotification(f'`{record}` is still *NOT PROPAGATED* as `{content}` at {", ".join(pending)} after `{config["propagation"]["deadline"]}` seconds.', True)
+            return
+        shutdownRequested.wait(config['propagation']['interval'])
+
+def instanceId():
+    return config['general']['instance_id'] or socket.gethostname()
+
+class InstanceConflictError(Exception):
+    pass
+
+ownedRecords = set() # Records this process has updated, so a newer comment of another instance means we are fighting over them
+instanceConflicts = {} # Record -> time.monotonic() until which it is left to the other instance, see backingOffFrom()
+
+def backingOffFrom(record):
+    # The conflict was reported once already, so don't even try (and fail) again until the window expired
+    return instanceConflicts.get(record, 0) > time.monotonic()
+
+def updateOwnedRecord(recordId, data, reason):
+    # Replicas deployed by accident would silently overwrite each other, so the record comment tells who updated it last
+    if config['general']['instance_conflict_window'] is not None:
+        for record in cloudflare.listRecords(data['name'], data['type']):
+            match = re.fullmatch(r'cname-switcher (\S+) (\S+)(?: .*)?', record.comment or '')
+            if record.id != recordId or record.name not in ownedRecords or match is None or match.group(1) == instanceId():
+                continue # Taking over e.g. after a redeploy is fine
+            try:
+                age = (datetime.datetime.now(datetime.timezone.utc) - datetime.datetime.fromisoformat(match.group(2))).total_seconds()
+            except ValueError:
+                continue # Not written by us after all
+            if age < config['general']['instance_conflict_window']:
+                backoff = config['general']['instance_conflict_window'] - age
+                instanceConflicts[data['name']] = time.monotonic() + backoff
+                message = f'{data["name"]} was updated by another switcher instance ({match.group(1)}) {age:.0f}s ago - leaving it alone for {backoff:.0f}s (see general.instance_id)'
+                logger.warning(message)
+                recordEvent('cloudflare', message)
+                sendTelegramNotification(message, False, ignorable=False)
+                raise InstanceConflictError(message)
+    comment = f'cname-switcher {instanceId()} {datetime.datetime.now(datetime.timezone.utc).strftime("%Y-%m-%dT%H:%M:%SZ")}'
+    if config['cloudflare']['comment_reason']:
+        comment += ' ' + reason # So whoever looks at the zone sees why the value is what it is
+    if len(comment) > config['cloudflare']['comment_max_length']:
+        comment = comment[:config['cloudflare']['comment_max_length'] - 3] + '...'
+    data = dict(data, comment=comment)
+    if len(config['cloudflare']['tags']):
+        data['tags'] = config['cloudflare']['tags']
+    result = cloudflare.updateRecord(recordId, data)
+    ownedRecords.add(data['name'])
+    return result
+
+def accountUplinkStates():
+    # Attribute the time since the previous check to the state each uplink was in, to derive SLAs
+    global lastUplinkAccounting
+    now = time.monotonic()
+    for name in ('primary', 'secondary'):
+        healthy = externalIpOwner == name
+        state = uplinkStates.get(name)
+        if state is not None:
+            metricUplinkStateDuration.labels(name=name, state='healthy' if state['healthy'] else 'unhealthy').inc(now - lastUplinkAccounting)
+        if state is None or state['healthy'] != healthy:
+            state = uplinkStates[name] = {'healthy': healthy, 'since': now, 'checks': 0}
+            storeHistory('uplinks', datetime.datetime.now(datetime.timezone.utc).isoformat(), name, 'healthy' if healthy else 'unhealthy') # For /reports/sla
+        state['checks'] += 1
+        metricUplinkHealthy.labels(name=name).set(1 if healthy else 0)
+        metricUplinkStreakDuration.labels(name=name).set(now - state['since'])
+        metricUplinkStreakChecks.labels(name=name).set(state['checks'])
+    lastUplinkAccounting = now
 
 # Prepare the healthcheck/metric endpoint
-loopTime = config['general']['update_interval']
 metricRegistry = CollectorRegistry()
+# The usual process_* (memory, open file descriptors, CPU time - Linux only), python_info and python_gc_* metrics, to diagnose small devices
+ProcessCollector(registry=metricRegistry)
+PlatformCollector(registry=metricRegistry)
+GCCollector(registry=metricRegistry)
+metricThreads = Gauge(args.metrics_prefix + '_threads', 'How many threads are running (e.g. open /events streams and http requests)?', registry=metricRegistry)
+metricThreads.set_function(threading.active_count)
+metricSyslogQueue = Gauge(args.metrics_prefix + '_syslog_queue_size', 'How many events are waiting to be sent to syslog?', registry=metricRegistry)
+metricSyslogQueue.set_function(syslogQueue.qsize)
 metricHealthy = Gauge(args.metrics_prefix + '_healthy', 'Everything OK?', registry=metricRegistry)
 metricDurations = Gauge(args.metrics_prefix + '_durations', 'How long did it take to update XY?', ['dimension'], registry=metricRegistry)
 metricCnameTarget = Enum(args.metrics_prefix + '_cname_target', 'Which CNAME is currently active?', states=['primary', 'secondary', 'undefined'], registry=metricRegistry)
 metricCnameTarget.state('undefined') # initially we don't have anything set
 metricExternalIp = Info(args.metrics_prefix + '_external_ip', 'Most recent external IP', registry=metricRegistry)
+metricExternalIpLastFailureReason = Info(args.metrics_prefix + '_external_ip_last_failure_reason', 'Why did the external IP resolution fail the last time?', registry=metricRegistry)
+metricCloudflareTokenValid = Gauge(args.metrics_prefix + '_cloudflare_token_valid', 'Did the Cloudflare token pass the check on the last (re)load?', registry=metricRegistry)
+metricCloudflareTokenValid.set_function(lambda: 1 if cloudflareTokenCheck is not None and cloudflareTokenCheck['success'] else 0)
+metricCloudflareRateLimitWait = Gauge(args.metrics_prefix + '_cloudflare_rate_limit_wait_seconds', 'How long did Cloudflare calls wait for the request budget in total?', registry=metricRegistry)
+metricCloudflareRateLimitWait.set_function(lambda: cloudflareBudget['waited'])
+metricCloudflareLastSuccess = Gauge(args.metrics_prefix + '_cloudflare_last_success_timestamp_seconds', 'When was the record updated successfully the last time?', ['record'], registry=metricRegistry)
+metricCloudflareLastAttemptResult = Gauge(args.metrics_prefix + '_cloudflare_last_attempt_result', 'Was the last update of the record successful (1) or not (0)?', ['record'], registry=metricRegistry)
+metricDnsPropagation = Gauge(args.metrics_prefix + '_dns_propagation_seconds', 'How long did the resolver take to return the last update of the record (see the propagation section)?', ['record', 'resolver'], registry=metricRegistry)
+metricPrimaryRemaining = Gauge(args.metrics_prefix + '_primary_remaining_seconds', 'How long until the primary is published again at the earliest (if every further check sees it)?', registry=metricRegistry)
+metricPrimaryRemaining.set_function(lambda: primaryRemainingSeconds()) # Defined below, next to the status
+metricUplinkHealthy = Gauge(args.metrics_prefix + '_uplink_healthy', 'Is the external IP currently in the subnets of the uplink?', ['name'], registry=metricRegistry)
+metricUplinkStateDuration = Counter(args.metrics_prefix + '_uplink_state_seconds_total', 'How long was the uplink in this state in total?', ['name', 'state'], registry=metricRegistry)
+metricUplinkStreakDuration = Gauge(args.metrics_prefix + '_uplink_streak_duration_seconds', 'How long is the uplink already in its current state?', ['name'], registry=metricRegistry)
+metricUplinkStreakChecks = Gauge(args.metrics_prefix + '_uplink_streak_checks', 'How many consecutive checks is the uplink already in its current state?', ['name'], registry=metricRegistry)
+for name in ('primary', 'secondary'):
+    for state in ('healthy', 'unhealthy'):
+        metricUplinkStateDuration.labels(name=name, state=state) # Export zeros too, so ratios can be calculated right away
+metricConfigReloadSuccess = Gauge(args.metrics_prefix + '_config_reload_success_timestamp', 'When was the config (re)loaded successfully the last time?', registry=metricRegistry)
+metricConfigReloadSuccess.set_to_current_time() # The initial load was successful, otherwise we would not be here
+metricBuildInfo = Info(args.metrics_prefix + '_build', 'Version of this script and of its Python interpreter', registry=metricRegistry)
+metricBuildInfo.info({'version': version, 'python': sys.version.split()[0]})
+metricTargetInfo = Info('target', 'Target metadata', registry=metricRegistry) # Standard (OpenMetrics) name, so it is not prefixed
+metricConfigInfo = Info(args.metrics_prefix + '_config', 'Hash of the active config, to correlate behavior changes with config rollouts', registry=metricRegistry)
+
+def updateInfoMetrics():
+    metricTargetInfo.info({'version': version, 'record': config['general']['dynamic_cname'], 'instance': instanceId()})
+    # The hash covers the resolved secrets too, so rotating one counts as a change (the secret itself can't be derived from it)
+    configHash = hashlib.sha256(json.dumps(config, sort_keys=True, default=str).encode('utf8')).hexdigest()[:12]
+    metricConfigInfo.info({'hash': configHash, 'version': version, 'record': config['general']['dynamic_cname']})
+updateInfoMetrics()
+dashboardHtml = """<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Cloudflare CNAME Switcher</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+td, th { padding: 0.3em 1em 0.3em 0; text-align: left; vertical-align: top; }
+.primary { color: #2a7d2a; } .secondary { color: #b36b00; } .undefined, .failed { color: #b00020; }
+#events td { font-family: monospace; font-size: 0.9em; }
+</style>
+</head>
+<body>
+<h1 id="title">Cloudflare CNAME Switcher</h1>
+<table>
+<tr><th>Published target</th><td id="target">?</td></tr>
+<tr><th>Primary confidence</th><td id="confidence">?</td></tr>
+<tr><th>External IPv4</th><td id="ip">?</td></tr>
+<tr><th>Next check</th><td id="next">?</td></tr>
+<tr><th>Last Cloudflare update</th><td id="cloudflare">?</td></tr>
+<tr><th>Queued notifications</th><td id="queued">?</td></tr>
+</table>
+<h2>Recent events</h2>
+<table id="events"></table>
+<script>
+let status = null, fetchedAt = 0;
+function text(id, value, cls) {
+    const e = document.getElementById(id);
+    e.textContent = value;
+    e.className = cls || '';
+}
+function render() {
+    if (status === null) return;
+    const age = (status.last_loop_age === null ? null : status.last_loop_age + (Date.now() - fetchedAt) / 1000);
+    document.getElementById('title').textContent = status.dynamic_cname;
+    text('target', status.target === 'undefined' ? 'undefined' : status.target + ' (' + status.targets[status.target] + ')', status.target);
+    let confidence = status.primary_confidence + ' / ' + status.primary_confidence_required;
+    if (status.target !== 'primary' && status.primary_confidence > 0) {
+        const remaining = status.primary_confidence_required - status.primary_confidence;
+        confidence += ' (switch to primary in ~' + Math.max(0, Math.round(remaining * status.update_interval - (age || 0))) + 's)';
+    }
+    text('confidence', confidence);
+    text('ip', status.external_ip || '?');
+    text('next', age === null ? 'pending' : (age > status.update_interval ? 'running...' : 'in ' + Math.round(status.update_interval - age) + 's'));
+    const cf = status.last_cloudflare_update;
+    if (cf === null) {
+        text('cloudflare', 'none yet');
+    } else {
+        text('cloudflare', cf.time + ': ' + cf.record + ' -> ' + cf.content + (cf.success ? '' : ' FAILED (' + cf.error + ')'), cf.success ? '' : 'failed');
+    }
+    text('queued', status.queued_notifications);
+    const table = document.getElementById('events');
+    table.replaceChildren();
+    for (const event of status.events.slice().reverse()) {
+        const row = table.insertRow();
+        row.insertCell().textContent = event.time;
+        row.insertCell().textContent = event.kind;
+        row.insertCell().textContent = event.message;
+    }
+}
+async function refresh() {
+    try {
+        const response = await fetch('status');
+        status = await response.json();
+        fetchedAt = Date.now();
+    } catch (e) {
+        text('next', 'status unavailable', 'failed');
+    }
+    render();
+}
+refresh();
+setInterval(refresh, 30000);
+const events = new EventSource('events');
+for (const kind of ['health', 'election', 'cloudflare', 'notification', 'config']) {
+    events.addEventListener(kind, refresh);
+}
+setInterval(render, 1000);
+</script>
+</body>
+</html>
+"""
+
+def currentTarget():
+    return 'undefined' if primaryActive is None else ('primary' if primaryActive else 'secondary')
+
+def primaryRemainingSeconds():
+    # How long the secondary stays published at least, if every further check sees the primary (0 if it is published already)
+    if primaryActive == True:
+        return 0
+    remaining = max(0, config['primary']['confidence'] - primaryConfidence) * loopTime
+    pin = pinnedTarget # Called by the scrapes, while the main loop may remove it
+    if pinTarget(pin) == 'primary':
+        remaining = loopTime # The next check seeing the primary publishes it
+    elif pinTarget(pin) == 'secondary' and pin['until'] is not None: # Without an expiry, the earliest time is unknown
+        remaining = max(remaining, (datetime.datetime.fromisoformat(pin['until']) - datetime.datetime.now(datetime.timezone.utc)).total_seconds())
+    return remaining
+
+def buildStatus():
+    lastLoop = HealthcheckMetricEndpoint.lastLoop
+    return {
+        'dynamic_cname': config['general']['dynamic_cname'],
+        'target': currentTarget(),
+        'targets': {
+            'primary': config['primary']['cname'],
+            'secondary': config['secondary']['cname']
+        },
+        'primary_confidence': primaryConfidence,
+        'primary_confidence_required': config['primary']['confidence'],
+        'primary_expected_at': None if primaryActive == True else (datetime.datetime.now(datetime.timezone.utc) + datetime.timedelta(seconds=primaryRemainingSeconds())).isoformat(),
+        'pin': pinnedTarget if activePin() is not None else None,
+        'planned_failovers': [{'target': entry['target'], 'start': entry['start'].isoformat(), 'end': entry['end'].isoformat(), 'phase': plannedFailoverPhases.get(entry['key'], 'scheduled')} for entry in plannedFailovers()],
+        'external_ip': None if externalIPv4 is None else str(externalIPv4),
+        'update_interval': loopTime,
+        'last_loop_age': None if lastLoop is None else (datetime.datetime.now() - lastLoop).total_seconds(),
+        'last_cloudflare_update': lastCloudflareUpdate,
+        'cloudflare_token_check': cloudflareTokenCheck,
+        'queued_notifications': len(notificationBuffer) + telegramQueue.qsize(),
+        'last_resolve_problem': lastResolveProblem,
+        'shadow': None if not args.shadow_config else {
+            'target': 'undefined' if shadowActive is None else ('primary' if shadowActive else 'secondary'),
+            'primary_confidence': shadowConfidence,
+            'primary_confidence_required': shadowConfig['primary']['confidence']
+        },
+        'uplinks': {name: {
+            'healthy': state['healthy'],
+            'streak_duration': time.monotonic() - state['since'],
+            'streak_checks': state['checks']
+        } for name, state in uplinkStates.items()},
+        'events': list(recentEvents)
+    }
+
 class HealthcheckMetricEndpoint(BaseHTTPRequestHandler):
     lastLoop = None
+    routes = {} # (method, path) -> handler, see registerRoute()
+    rateLimitBuckets = {} # Client address -> (tokens, last refill), see rejectRateLimited()
+    rateLimitLock = threading.Lock()
 
-    def do_GET(self):
+    def setup(self):
+        # Idle (or never finishing TLS-handshaking) clients would otherwise keep their slot of http.max_connections forever
+        self.timeout = config['general']['timeout']
+        super().setup()
+
+    def sendBody(self, code, contentType, body):
+        self.send_response(code)
+        self.send_header('Content-type', contentType)
+        self.send_header('Content-length', len(body))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def isAuthorized(self):
+        if httpAuthToken is None and httpAuthBasic is None:
+            return True
+        if httpAuthExemptHealthz and urllib.parse.urlsplit(self.path).path.endswith('/healthz'):
+            return True
+        if httpPublicStatus and urllib.parse.urlsplit(self.path).path.endswith(('/public/status', '/public/status.json')):
+            return True
+        authorization = self.headers.get('Authorization', '')
+        if httpAuthToken is not None and authorization.startswith('Bearer '):
+            return hmac.compare_digest(authorization[len('Bearer '):].encode('utf8'), httpAuthToken.encode('utf8'))
+        if httpAuthBasic is not None and authorization.startswith('Basic '):
+            try:
+                credentials = base64.b64decode(authorization[len('Basic '):], validate=True)
+            except ValueError:
+                return False
+            return hmac.compare_digest(credentials, httpAuthBasic.encode('utf8'))
+        return False
+
+    def rejectUnauthorized(self):
+        if self.isAuthorized():
+            return False
+        self.send_response(401)
+        if httpAuthBasic is not None:
+            self.send_header('WWW-Authenticate', 'Basic realm="cname_switcher"')
+        self.end_headers()
+        return True
+
+    def rejectRateLimited(self):
+        # Token bucket per client address: rate_limit requests per second, but bursts of up to rate_limit_burst
+        if httpRateLimit is None:
+            return False
+        client = self.client_address[0] if isinstance(self.client_address, tuple) else 'unix'
+        now = time.monotonic()
+        with self.rateLimitLock:
+            if len(self.rateLimitBuckets) > 1000:
+                # Forget idle clients (their bucket would be full again anyways)
+                for idleClient in [c for c, (tokens, refill) in self.rateLimitBuckets.items() if tokens + (now - refill) * httpRateLimit >= httpRateLimitBurst]:
+                    del self.rateLimitBuckets[idleClient]
+            tokens, refill = self.rateLimitBuckets.get(client, (httpRateLimitBurst, now))
+            tokens = min(httpRateLimitBurst, tokens + (now - refill) * httpRateLimit)
+            allowed = tokens >= 1
+            self.rateLimitBuckets[client] = (tokens - 1 if allowed else tokens, now)
+        if allowed:
+            return False
+        self.send_response(429)
+        self.send_header('Retry-After', str(max(1, int((1 - tokens) / httpRateLimit + 0.5))))
+        self.end_headers()
+        return True
+
+    @classmethod
+    def registerRoute(cls, method, path, handler):
+        # Everything served by the internal http server registers here, handler(request) gets the request handler
+        cls.routes[(method, path)] = handler
+
+    def dispatch(self, method):
         self.protocol_version = 'HTTP/1.0'
-        okay = self.lastLoop is not None and datetime.datetime.now() - self.lastLoop < datetime.timedelta(seconds=loopTime * 2)
-        metricHealthy.set(1 if okay else 0)
-        if self.path.endswith('/healthz'):
-            msg = ('OK' if okay else 'BAD').encode('utf8')
-            self.send_response(200 if okay else 503)
-            self.send_header('Content-type', 'text/plain')
-            self.send_header('Content-length', len(msg))
-            self.end_headers()
-            self.wfile.write(msg)
-        elif self.path.endswith('/metrics'):
+        if self.rejectRateLimited() or self.rejectUnauthorized():
+            return
+        if method == 'POST' and httpAuthToken is None and httpAuthBasic is None:
+            # Everything changing the state (e.g. /admin/pin redirecting the traffic) is refused, unless only authorized clients can reach it
+            self.sendJson(403, {'success': False, 'error': 'POST requests require http.auth_token or http.auth_basic to be set'})
+            return
+        path = urllib.parse.urlsplit(self.path).path
+        for (routeMethod, routePath), handler in sorted(self.routes.items(), key=lambda route: -len(route[0][1])): # Longest first, e.g. /history/events before /events
+            # Matched as suffix, so the server also works behind a reverse proxy with a path prefix
+            if routeMethod == method and (path == routePath if routePath == '/' else path.endswith(routePath)):
+                handler(self)
+                return
+        self.send_response(404)
+        self.end_headers()
+
+    def do_GET(self):
+        self.dispatch('GET')
+
+    def do_POST(self):
+        self.dispatch('POST')
+
+    def sendJson(self, code, data):
+        self.sendBody(code, 'application/json', json.dumps(data).encode('utf8'))
+
+    def streamEvents(self):
+        # Server-Sent Events: Stream every new event until the client disconnects
+        subscriber = queue.Queue(maxsize=100)
+        with eventSubscribersLock:
+            eventSubscribers.append(subscriber)
+        try:
             self.send_response(200)
-            self.send_header('Content-type', 'text/plain')
-            self.end_headers()
-            self.wfile.write(generate_latest(metricRegistry))
-        else:
-            self.send_response(404)
+            self.send_header('Content-type', 'text/event-stream')
+            self.send_header('Cache-Control', 'no-cache')
             self.end_headers()
+            self.wfile.flush()
+            while not shutdownRequested.is_set():
+                try:
+                    event = subscriber.get(timeout=15)
+                    if event is None:
+                        break # Shutdown
+                    self.wfile.write(f'event: {event["kind"]}\ndata: {json.dumps(event)}\n\n'.encode('utf8'))
+                except queue.Empty:
+                    self.wfile.write(b': keepalive\n\n') # Detects disconnected clients and keeps proxies from closing the stream
+                self.wfile.flush()
+        except OSError:
+            pass # Client disconnected or stopped reading (the writes time out after general.timeout)
+        finally:
+            with eventSubscribersLock:
+                eventSubscribers.remove(subscriber)
 
     def log_message(self, format, *args):
         # Do not print the healthcheck requests to the console!
         return
 
-healthcheckServer = HTTPServer(('0.0.0.0', args.port), HealthcheckMetricEndpoint)
-healthcheckThread = threading.Thread(target=healthcheckServer.serve_forever)
-healthcheckThread.daemon = True # Disconnect from main thread
-healthcheckThread.start()
+def processHealthy():
+    lastLoop = HealthcheckMetricEndpoint.lastLoop
+    return lastLoop is not None and datetime.datetime.now() - lastLoop < datetime.timedelta(seconds=loopTime * 2) and len(wedgedTasks) == 0
+metricHealthy.set_function(lambda: 1 if processHealthy() else 0)
 
-# Configure the ipgetter
-getter = IPGetter()
-getter.timeout = config['general']['timeout']
+def serveHealthz(request):
+    okay = processHealthy()
+    msg = 'OK' if okay else 'BAD'
+    if okay and httpHealthzMode == 'ingress':
+        # Also report problems of what we are publishing, so the orchestrator can alert/restart
+        if externalIpOwner == 'error' or (externalIpOwner == 'none' and primarySubnetsGiven and secondarySubnetsGiven):
+            okay, msg = False, 'BAD: no healthy uplink'
+        elif cloudflareFailuresInRow >= httpHealthzCloudflareFailures:
+            okay, msg = False, f'BAD: the last {cloudflareFailuresInRow} Cloudflare updates failed'
+    request.sendBody(200 if okay else 503, 'text/plain', msg.encode('utf8'))
+
+def buildMetricsJson():
+    # The registry as JSON for scripts that can't parse the text format - JSON has no Infinity or NaN, so those become strings as in the text format
+    metrics = {}
+    for family in metricRegistry.collect():
+        metrics[family.name] = {
+            'type': family.type,
+            'help': family.documentation,
+            'samples': [{
+                'name': sample.name,
+                'labels': sample.labels,
+                'value': sample.value if math.isfinite(sample.value) else {math.inf: '+Inf', -math.inf: '-Inf'}.get(sample.value, 'NaN')
+            } for sample in family.samples]
+        }
+    return {'metrics': metrics, 'status': buildStatus()}
+
+def buildPublicStatus():
+    # Only what customers may see: which site is active since when, but neither IPs nor internals
+    since = None
+    for decision in reversed(list(decisionHistory)):
+        if decision['cloudflare_success'] and decision['previous_target'] != decision['target']:
+            since = decision['time']
+            break
+    return {
+        'title': httpPublicStatusTitle or config['general']['dynamic_cname'],
+        'active': currentTarget(),
+        'since': since,
+        'uplinks': {name: 'operational' if state['healthy'] else 'outage' for name, state in uplinkStates.items()},
+        'updated': datetime.datetime.now(datetime.timezone.utc).isoformat()
+    }
+
+def servePublicStatus(request, asJson):
+    if not httpPublicStatus:
+        request.sendJson(404, {'success': False, 'error': 'http.public_status is not enabled'})
+        return
+    status = buildPublicStatus()
+    if asJson:
+        request.sendJson(200, status)
+        return
+    uplinks = ''.join(f'<tr><th>{html.escape(name.capitalize())} site</th><td class="{state}">{state}</td></tr>' for name, state in status['uplinks'].items())
+    page = f'''<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="60">
+<title>{html.escape(status['title'])} - Status</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; color: #222; }}
+td, th {{ padding: 0.3em 1em 0.3em 0; text-align: left; }}
+.operational {{ color: #2a7d2a; }} .outage {{ color: #b00020; }}
+</style>
+</head>
+<body>
+<h1>{html.escape(status['title'])}</h1>
+<p>Served by the <b>{html.escape(status['active'])}</b> site{'' if status['since'] is None else ' since ' + html.escape(status['since'])}.</p>
+<table>{uplinks}</table>
+<p><small>Updated {html.escape(status['updated'])}</small></p>
+</body>
+</html>
+'''
+    request.sendBody(200, 'text/html; charset=utf-8', page.encode('utf8'))
+
+def serveHistory(request, table, inMemory):
+    # ?from= and ?to= are ISO timestamps (UTC if without offset), ?limit= caps the number of (most recent) results
+    query = urllib.parse.parse_qs(urllib.parse.urlsplit(request.path).query)
+    try:
+        bounds = []
+        for name in ('from', 'to'):
+            value = query.get(name, [None])[0]
+            if value is not None:
+                value = datetime.datetime.fromisoformat(value)
+                value = (value if value.tzinfo is not None else value.replace(tzinfo=datetime.timezone.utc)).astimezone(datetime.timezone.utc).isoformat()
+            bounds.append(value)
+        limit = int(query.get('limit', ['10000'])[0])
+        assert limit > 0, 'limit should be positive'
+    except (ValueError, AssertionError) as e:
+        request.sendJson(422, {'success': False, 'error': str(e)})
+        return
+    since, until = bounds
+    if config['history']['database'] is None:
+        rows = [row for row in list(inMemory) if (since is None or row['time'] >= since) and (until is None or row['time'] <= until)][-limit:]
+    else:
+        try:
+            rows = queryHistory(table, since, until, limit)
+        except sqlite3.Error as e:
+            request.sendJson(500, {'success': False, 'error': str(e)})
+            return
+        if table == 'decisions':
+            rows = [json.loads(row['decision']) for row in rows]
+    request.sendJson(200, rows)
+
+def buildSlaReport(period):
+    # Availability of each uplink within the period, only counting the time it was monitored at all
+    until = datetime.datetime.now(datetime.timezone.utc)
+    since = until - datetime.timedelta(seconds=period)
+    with historyDatabaseLock:
+        connection = openHistoryDatabase()
+        try:
+            report = {'from': since.isoformat(), 'to': until.isoformat(), 'uplinks': {}}
+            for name in ('primary', 'secondary'):
+                # The state at the start of the period, followed by all changes within it
+                rows = connection.execute('SELECT time, state FROM uplinks WHERE name = ? AND time < ? ORDER BY time DESC LIMIT 1', (name, since.isoformat())).fetchall()
+                rows = [(since.isoformat(), state) for _, state in rows]
+                rows += connection.execute('SELECT time, state FROM uplinks WHERE name = ? AND time >= ? ORDER BY time', (name, since.isoformat())).fetchall()
+                monitored, healthy, outages, outage = 0, 0, [], None
+                for (start, state), (end, _) in zip(rows, rows[1:] + [(until.isoformat(), None)]):
+                    duration = (datetime.datetime.fromisoformat(end) - datetime.datetime.fromisoformat(start)).total_seconds()
+                    if state != 'unknown':
+                        monitored += duration
+                    if state == 'healthy':
+                        healthy += duration
+                        if outage is not None:
+                            outages.append(outage) # Recovered
+                            outage = None
+                    elif state == 'unhealthy':
+                        outage = (outage or 0) + duration # An unknown gap does not end the outage
+                longest = max(outages + ([] if outage is None else [outage]), default=0)
+                report['uplinks'][name] = {
+                    'availability': None if monitored == 0 else healthy / monitored,
+                    'monitored_seconds': monitored,
+                    'healthy_seconds': healthy,
+                    'outages': len(outages) + (0 if outage is None else 1),
+                    'ongoing_outage_seconds': outage,
+                    'mttr_seconds': None if len(outages) == 0 else sum(outages) / len(outages),
+                    'longest_outage_seconds': longest,
+                    'switched_away': 0
+                }
+            for (decision,) in connection.execute('SELECT decision FROM decisions WHERE time >= ?', (since.isoformat(),)):
+                decision = json.loads(decision)
+                if decision['cloudflare_success'] and decision['previous_target'] in report['uplinks'] and decision['previous_target'] != decision['target']:
+                    report['uplinks'][decision['previous_target']]['switched_away'] += 1 # For the primary these are the failovers
+        finally:
+            connection.close()
+    return report
+
+def serveSlaReport(request):
+    # ?period= (e.g. 30d) and ?format=json or csv
+    if config['history']['database'] is None:
+        request.sendJson(409, {'success': False, 'error': 'history.database should be set, the report is built from it'})
+        return
+    query = urllib.parse.parse_qs(urllib.parse.urlsplit(request.path).query)
+    try:
+        period = parseDuration(query.get('period', ['30d'])[0])
+        assert period > 0, 'period should be positive'
+        outputFormat = query.get('format', ['json'])[0]
+        assert outputFormat in ('json', 'csv'), 'format should be json or csv'
+    except (ValueError, AssertionError) as e:
+        request.sendJson(422, {'success': False, 'error': str(e)})
+        return
+    try:
+        report = buildSlaReport(period)
+    except sqlite3.Error as e:
+        request.sendJson(500, {'success': False, 'error': str(e)})
+        return
+    if outputFormat == 'json':
+        request.sendJson(200, report)
+        return
+    columns = ['availability', 'monitored_seconds', 'healthy_seconds', 'outages', 'ongoing_outage_seconds', 'mttr_seconds', 'longest_outage_seconds', 'switched_away']
+    lines = ['uplink,from,to,' + ','.join(columns)]
+    for name, values in report['uplinks'].items():
+        lines.append(','.join([name, report['from'], report['to']] + ['' if values[c] is None else str(values[c]) for c in columns]))
+    request.sendBody(200, 'text/csv', ('\n'.join(lines) + '\n').encode('utf8'))
+
+def reloadConfig(request):
+    # Reloads like SIGHUP does (by the main loop, so e.g. also the shadow config) and reports the outcome
+    global reloadRequested
+    waiter = {'done': threading.Event(), 'outcome': None}
+    with reloadWaitersLock:
+        reloadWaiters.append(waiter)
+    reloadRequested = True
+    wakeUp.set()
+    if not waiter['done'].wait(loopTime + config['watchdog']['grace']): # The main loop is stuck in a check
+        request.sendJson(202, {'success': None, 'error': 'The reload is still pending'})
+        return
+    request.sendJson(200 if waiter['outcome']['success'] else 422, waiter['outcome'])
+
+def triggerProbe(request):
+    # E.g. for deploy pipelines: Check right away instead of waiting up to general.update_interval (a switch follows if needed)
+    logger.info('Check requested via /hooks/probe.')
+    wakeUp.set()
+    request.sendJson(202, {'success': True, 'last_loop_age': None if HealthcheckMetricEndpoint.lastLoop is None else (datetime.datetime.now() - HealthcheckMetricEndpoint.lastLoop).total_seconds()})
+
+def changeChaos(request):
+    # Adjust the chaos settings at runtime (until the next reload) - only if the config allows chaos at all
+    if not config['chaos']['enabled']:
+        request.sendJson(403, {'success': False, 'error': 'chaos.enabled is not set in the config'})
+        return
+    try:
+        changes = json.loads(request.rfile.read(int(request.headers.get('Content-Length', 0))) or b'{}')
+        assert isinstance(changes, dict), 'body should be a JSON object'
+        problems = validateConfig(changes, configSchema['chaos'], 'chaos.')
+        assert len(problems) == 0, ', '.join(problems)
+    except (ValueError, AssertionError) as e:
+        request.sendJson(422, {'success': False, 'error': str(e)})
+        return
+    if 'resolver_delay' in changes:
+        changes['resolver_delay'] = parseDuration(changes['resolver_delay'])
+    chaosSettings.update(changes)
+    logger.warning(f'Chaos settings changed: {chaosSettings}')
+    recordEvent('config', f'Chaos settings changed: {chaosSettings}')
+    request.sendJson(200, {'success': True, 'chaos': chaosSettings})
+
+def changePin(request):
+    # E.g. during a cutover: {"target": "secondary", "duration": "2h"} (or "until": ISO timestamp), {"target": null} removes the pin
+    global pinnedTarget
+    try:
+        changes = json.loads(request.rfile.read(int(request.headers.get('Content-Length', 0))) or b'{}')
+        assert isinstance(changes, dict), 'body should be a JSON object'
+        assert set(changes) <= {'target', 'duration', 'until'}, 'only target, duration and until are allowed'
+        assert checkPinTarget(changes.get('target')) is None, 'target should be primary, secondary or null'
+        if changes.get('target') is None:
+            pin = None
+        else:
+            assert ('duration' in changes) != ('until' in changes), 'either duration or until should be given'
+            if 'duration' in changes:
+                assert checkDuration(changes['duration']) is None, checkDuration(changes['duration'])
+                until = datetime.datetime.now(datetime.timezone.utc) + datetime.timedelta(seconds=parseDuration(changes['duration']))
+            else:
+                until = parseTimestamp(changes['until'])
+            assert until > datetime.datetime.now(datetime.timezone.utc), 'the pin should not expire in the past'
+            pin = {'target': changes['target'], 'until': until.isoformat(), 'source': 'admin'}
+    except (ValueError, TypeError, AssertionError) as e:
+        request.sendJson(422, {'success': False, 'error': str(e)})
+        return
+    pinnedTarget = pin
+    if pin is None:
+        logger.warning('Pin removed via /admin/pin.')
+        recordEvent('election', 'Pin removed, the election decides again')
+    else:
+        logger.warning(f'Pinned to {pin["target"]} until {pin["until"]} via /admin/pin.')
+        recordEvent('election', f'Pinned to {pin["target"]} until {pin["until"]}')
+    wakeUp.set() # Applied by the next check
+    request.sendJson(200, {'success': True, 'pin': pin})
+
+def previewElection(request):
+    # What-if: Runs the election for hypothetical checks, without touching Cloudflare or the real state
+    query = urllib.parse.parse_qs(urllib.parse.urlsplit(request.path).query)
+    try:
+        checks = int(query.get('checks', ['1'])[0])
+        assert 1 <= checks <= 1000, 'checks should be between 1 and 1000'
+        unhealthy = [n for v in query.get('assume_unhealthy', []) for n in v.split(',') if n]
+        assert set(unhealthy) <= {'primary', 'secondary'}, 'assume_unhealthy should list primary and/or secondary'
+        if 'ip' in query:
+            owner = subnetOwner(ipaddress.ip_address(query['ip'][0]), configSubnets)
+        elif 'primary' not in unhealthy:
+            owner = 'primary'
+        elif 'secondary' not in unhealthy:
+            owner = 'secondary'
+        else:
+            owner = 'error' # As if no uplink works
+    except (ValueError, AssertionError) as e:
+        request.sendJson(422, {'success': False, 'error': str(e)})
+        return
+    confidence, active = primaryConfidence, primaryActive
+    steps = []
+    for check in range(1, checks + 1):
+        confidence = nextPrimaryConfidence(confidence, owner, configSubnets)
+        switchTo = electedTarget(confidence, active, config['primary']['confidence'])
+        if switchTo is not None:
+            active = switchTo == 'primary' # Assuming the Cloudflare update succeeds
+        steps.append({'check': check, 'primary_confidence': confidence, 'switch_to': switchTo})
+    target = 'undefined' if active is None else ('primary' if active else 'secondary')
+    request.sendJson(200, {
+        'success': True,
+        'assumed_owner': owner,
+        'current': {'target': currentTarget(), 'primary_confidence': primaryConfidence},
+        'steps': steps,
+        'target': target,
+        'published': None if active is None else dynamicCnameData(config, target)
+    })
+
+registerRoute = HealthcheckMetricEndpoint.registerRoute
+registerRoute('GET', '/healthz', serveHealthz)
+registerRoute('GET', '/metrics', lambda request: request.sendBody(200, 'text/plain', generate_latest(metricRegistry)))
+registerRoute('GET', '/metrics.json', lambda request: request.sendJson(200, buildMetricsJson()))
+registerRoute('GET', '/status', lambda request: request.sendJson(200, buildStatus()))
+registerRoute('GET', '/events', lambda request: request.streamEvents())
+registerRoute('GET', '/history', lambda request: serveHistory(request, 'decisions', decisionHistory))
+registerRoute('GET', '/reports/sla', serveSlaReport)
+registerRoute('GET', '/public/status', lambda request: servePublicStatus(request, False))
+registerRoute('GET', '/public/status.json', lambda request: servePublicStatus(request, True))
+registerRoute('GET', '/history/events', lambda request: serveHistory(request, 'events', recentEvents))
+registerRoute('GET', '/history/checks', lambda request: request.sendJson(200, list(checkHistory)))
+registerRoute('GET', '/', lambda request: request.sendBody(200, 'text/html; charset=utf-8', dashboardHtml.encode('utf8')))
+registerRoute('POST', '/admin/reload', reloadConfig)
+registerRoute('POST', '/hooks/probe', triggerProbe)
+registerRoute('GET', '/admin/chaos', lambda request: request.sendJson(200, chaosSettings))
+registerRoute('POST', '/admin/chaos', changeChaos)
+registerRoute('GET', '/admin/election/preview', previewElection)
+registerRoute('GET', '/admin/pin', lambda request: request.sendJson(200, pinnedTarget if activePin() is not None else None))
+registerRoute('POST', '/admin/pin', changePin)
+
+class ConnectionLimitMixIn:
+    # Caps the concurrently handled connections (including /events streams) over all servers
+    openConnections = 0
+    openConnectionsLock = threading.Lock()
+
+    def process_request(self, request, client_address):
+        with ConnectionLimitMixIn.openConnectionsLock:
+            accepted = ConnectionLimitMixIn.openConnections < httpMaxConnections
+            if accepted:
+                ConnectionLimitMixIn.openConnections += 1
+        if not accepted:
+            logger.warning(f'Too many connections to the healthcheck/metric endpoint, dropping the one from {client_address or "unix socket"}.')
+            self.shutdown_request(request)
+            return
+        super().process_request(request, client_address)
+
+    def process_request_thread(self, request, client_address):
+        try:
+            super().process_request_thread(request, client_address)
+        finally:
+            with ConnectionLimitMixIn.openConnectionsLock:
+                ConnectionLimitMixIn.openConnections -= 1
+
+class LimitedThreadingHTTPServer(ConnectionLimitMixIn, ThreadingHTTPServer):
+    pass
+
+class ThreadingHTTPServerV6(LimitedThreadingHTTPServer):
+    address_family = socket.AF_INET6
+
+if hasattr(socketserver, 'ThreadingUnixStreamServer'): # Not on Windows
+    class ThreadingUnixHTTPServer(ConnectionLimitMixIn, socketserver.ThreadingUnixStreamServer):
+        def server_bind(self):
+            if os.path.exists(self.server_address):
+                os.remove(self.server_address) # Stale socket of a previous run
+            super().server_bind()
+
+        def server_close(self):
+            super().server_close()
+            os.remove(self.server_address)
+
+def createHealthcheckServer(bind):
+    if bind.startswith('unix:'):
+        assert hasattr(socketserver, 'ThreadingUnixStreamServer'), 'unix sockets are not supported on this platform'
+        server = ThreadingUnixHTTPServer(bind[len('unix:'):], HealthcheckMetricEndpoint)
+    else:
+        host, port = bind.rsplit(':', 1)
+        if host.startswith('[') and host.endswith(']'):
+            server = ThreadingHTTPServerV6((host[1:-1], int(port)), HealthcheckMetricEndpoint)
+        else:
+            server = LimitedThreadingHTTPServer((host, int(port)), HealthcheckMetricEndpoint)
+    server.daemon_threads = True # Do not wait for open /events streams on shutdown
+    return server
+
+if httpTlsCert is not None:
+    healthcheckTlsContext = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+    healthcheckTlsContext.load_cert_chain(httpTlsCert, httpTlsKey)
+
+healthcheckServers = []
+for bind in (args.bind or [f'0.0.0.0:{args.port}']):
+    healthcheckServer = createHealthcheckServer(bind)
+    if httpTlsCert is not None:
+        # The handshake is done by the request thread, so a slow client can't block the accept loop
+        healthcheckServer.socket = healthcheckTlsContext.wrap_socket(healthcheckServer.socket, server_side=True, do_handshake_on_connect=False)
+    healthcheckThread = threading.Thread(target=healthcheckServer.serve_forever)
+    healthcheckThread.daemon = True # Disconnect from main thread
+    healthcheckThread.start()
+    healthcheckServers.append(healthcheckServer)
+    logger.debug(f'Healthcheck/metric endpoint is listening on {bind}')
+
+def pushMetrics():
+    # For deployments which can't be scraped: Push the same registry, which /metrics serves
+    if args.simulate:
+        logger.info('[Simulation] Metrics push to ' + config['metrics']['push_gateway'])
+        return
+    try:
+        push_to_gateway(config['metrics']['push_gateway'], job=config['metrics']['push_job'], registry=metricRegistry, grouping_key=config['metrics']['push_grouping_key'], timeout=config['general']['timeout'])
+        logger.debug('Pushed the metrics to ' + config['metrics']['push_gateway'])
+    except Exception:
+        logger.exception('Metrics push error.')
+
+def pushMetricsPeriodically():
+    reportProgress('metrics_push', config['metrics']['push_interval'])
+    while not shutdownRequested.wait(config['metrics']['push_interval']):
+        if config['metrics']['push_gateway'] is not None: # Can be changed by a reload
+            pushMetrics()
+        reportProgress('metrics_push', config['metrics']['push_interval'])
+
+metricsPushThread = threading.Thread(target=supervised('metrics_push', pushMetricsPeriodically))
+metricsPushThread.daemon = True
+metricsPushThread.start()
+
+def sendHeartbeat():
+    # Dead man's switch (e.g. healthchecks.io): Only sent while the checks are running, so silence means trouble
+    if not processHealthy():
+        logger.warning('Skipping the heartbeat, as the checks are not running.')
+        return
+    if config['heartbeat']['url'] is not None:
+        payload = {
+            'target': currentTarget(),
+            'external_ip': None if externalIPv4 is None else str(externalIPv4),
+            'last_cloudflare_success': lastCloudflareSuccess
+        }
+        if args.simulate:
+            logger.info(f'[Simulation] Heartbeat to {config["heartbeat"]["url"]}: {payload}')
+        else:
+            try:
+                openUrl(config, 'POST', config['heartbeat']['url'], payload).close()
+                logger.debug('Sent the heartbeat.')
+            except Exception:
+                logger.exception('Heartbeat error.')
+    if config['heartbeat']['telegram']:
+        sendTelegramNotification(f'Still alive: The {currentTarget()} CNAME is active, Cloudflare was updated successfully the last time at {lastCloudflareSuccess or "never (since startup)"}.', False, ignorable=False)
+
+def sendHeartbeatsPeriodically():
+    reportProgress('heartbeat', config['heartbeat']['interval'])
+    while not shutdownRequested.wait(config['heartbeat']['interval']):
+        sendHeartbeat()
+        reportProgress('heartbeat', config['heartbeat']['interval'])
+
+heartbeatThread = threading.Thread(target=supervised('heartbeat', sendHeartbeatsPeriodically))
+heartbeatThread.daemon = True
+heartbeatThread.start()
+
+metricTaskLastProgress = Gauge(args.metrics_prefix + '_task_last_progress_timestamp_seconds', 'When did the background task make progress the last time?', ['task'], registry=metricRegistry)
+
+def watchdog():
+    # A wedged thread (e.g. a send which never returns) would otherwise hang silently, as the others keep running
+    while not shutdownRequested.wait(5):
+        now = time.monotonic()
+        for task, (lastProgress, deadline) in list(taskProgress.items()):
+            metricTaskLastProgress.labels(task=task).set(lastProgress)
+            if deadline is None or now < deadline:
+                wedgedTasks.discard(task)
+                continue
+            if task in wedgedTasks:
+                continue # Already reported
+            wedgedTasks.add(task)
+            message = f'The {task} task made no progress since {datetime.datetime.fromtimestamp(lastProgress, datetime.timezone.utc).isoformat()}, it seems to be stuck.'
+            logger.error(message)
+            recordEvent('health', message)
+            if not config['watchdog']['exit']:
+                sendTelegramNotification(message, False, ignorable=False)
+                continue
+            logger.critical('Exiting, so the process gets restarted (see watchdog.exit)...')
+            if telegramToken is not None and not args.simulate:
+                for target in telegramTargets:
+                    try:
+                        deliverTelegramNotification(message, False, target) # Synchronously, the sender thread dies with us (or is the stuck task)
+                    except Exception:
+                        logger.exception('Could not send the watchdog notification.')
+            logging.shutdown()
+            os._exit(3) # The stuck thread would block a regular exit
+
+watchdogThread = threading.Thread(target=supervised('watchdog', watchdog))
+watchdogThread.daemon = True
+watchdogThread.start()
+
+# SNMPv2c traps are BER-encoded by hand, see CCS-SWITCHER-MIB.txt for their definition
+startedAt = time.monotonic()
+snmpTraps = {'failover': 1, 'outage': 2}
+snmpTrapValues = {'record': 1, 'previous_target': 2, 'target': 3, 'reason': 4}
+
+def berEncode(tag, payload):
+    length = len(payload)
+    if length < 0x80:
+        return bytes([tag, length]) + payload
+    lengthBytes = length.to_bytes((length.bit_length() + 7) // 8, 'big')
+    return bytes([tag, 0x80 | len(lengthBytes)]) + lengthBytes + payload
+
+def berInteger(value, tag=0x02):
+    return berEncode(tag, value.to_bytes(value.bit_length() // 8 + 1, 'big', signed=True))
+
+def berOid(oid):
+    parts = [int(p) for p in oid.split('.')]
+    payload = b''
+    for part in [parts[0] * 40 + parts[1]] + parts[2:]:
+        chunk = [part & 0x7f]
+        while part > 0x7f:
+            part >>= 7
+            chunk.insert(0, 0x80 | (part & 0x7f))
+        payload += bytes(chunk)
+    return berEncode(0x06, payload)
+
+def sendSnmpTrap(trap, values):
+    # Fire and forget, like traps are meant to be
+    if len(config['snmp']['targets']) == 0:
+        return
+    if args.simulate:
+        logger.info(f'[Simulation] SNMP trap {trap}: {values}')
+        return
+    base = config['snmp']['enterprise_oid']
+    varbinds = [
+        berEncode(0x30, berOid('1.3.6.1.2.1.1.3.0') + berInteger(int((time.monotonic() - startedAt) * 100) % 2**32, 0x43)), # sysUpTime.0
+        berEncode(0x30, berOid('1.3.6.1.6.3.1.1.4.1.0') + berOid(f'{base}.0.{snmpTraps[trap]}')) # snmpTrapOID.0
+    ]
+    for key, value in values.items():
+        varbinds.append(berEncode(0x30, berOid(f'{base}.1.{snmpTrapValues[key]}.0') + berEncode(0x04, str(value).encode('utf8'))))
+    pdu = berEncode(0xa7, berInteger(random.randint(0, 2**31 - 1)) + berInteger(0) + berInteger(0) + berEncode(0x30, b''.join(varbinds)))
+    message = berEncode(0x30, berInteger(1) + berEncode(0x04, config['snmp']['community'].encode('utf8')) + pdu) # Version 1 is SNMPv2c
+    for target in config['snmp']['targets']:
+        host, _, port = target.rpartition(':') if re.search(r':\d+$', target) else (target, None, '162')
+        try:
+            for family, _, _, _, address in socket.getaddrinfo(host.strip('[]'), int(port), type=socket.SOCK_DGRAM):
+                with socket.socket(family, socket.SOCK_DGRAM) as snmpSocket:
+                    snmpSocket.sendto(message, address)
+                break
+            logger.debug(f'Sent SNMP trap {trap} to {target}')
+        except OSError:
+            logger.exception(f'Could not send the SNMP trap to {target}.')
+
+def handleSighup(signum, frame):
+    global reloadRequested
+    reloadRequested = True
+    setFromSignalHandler(wakeUp)
+    if httpTlsCert is not None:
+        try:
+            healthcheckTlsContext.load_cert_chain(httpTlsCert, httpTlsKey)
+            logger.info('Reloaded TLS certificate of the healthcheck/metric endpoint.')
+        except Exception:
+            logger.exception('Could not reload TLS certificate, keeping the previous one.')
+if hasattr(signal, 'SIGHUP'): # Not on Windows, use /admin/reload there
+    signal.signal(signal.SIGHUP, handleSighup)
+
+def dumpState():
+    # Everything needed to debug a stuck or misbehaving switcher, without restarting it
+    state = {
+        'status': buildStatus(),
+        'primary_active': primaryActive,
+        'uplink_states': uplinkStates,
+        'dyndns_candidate': dyndnsCandidate,
+        'cloudflare_budget': cloudflareBudget,
+        'cloudflare_failures_in_row': cloudflareFailuresInRow,
+        'record_takeovers': recordTakeovers,
+        'owned_records': sorted(ownedRecords),
+        'pending_notifications': [{'message': message, 'time': timestamp, 'target': target} for message, markdown, timestamp, delayed, target in list(notificationBuffer)],
+        'chaos': chaosSettings,
+        'threads': sorted(t.name for t in threading.enumerate())
+    }
+    logger.info('State dump: ' + json.dumps(state, default=str))
+
+def handleSigusr1(signum, frame):
+    threading.Thread(target=dumpState, daemon=True).start() # Not within the signal handler, as it may have interrupted a log call
+if hasattr(signal, 'SIGUSR1'):
+    signal.signal(signal.SIGUSR1, handleSigusr1)
+
+def handleSigusr2(signum, frame):
+    global reconcileRequested
+    reconcileRequested = True
+    setFromSignalHandler(wakeUp)
+if hasattr(signal, 'SIGUSR2'):
+    signal.signal(signal.SIGUSR2, handleSigusr2)
+
+def handleShutdown(signum, frame):
+    if shutdownRequested.is_set() and signum == signal.SIGINT:
+        raise KeyboardInterrupt # Second Ctrl+C -> do not wait any longer
+    logger.info(f'Received {signal.Signals(signum).name}, stopping after the current check...')
+    setFromSignalHandler(shutdownRequested, wakeUp)
+signal.signal(signal.SIGTERM, handleShutdown)
+signal.signal(signal.SIGINT, handleShutdown)
+if hasattr(signal, 'SIGBREAK'):
+    signal.signal(signal.SIGBREAK, handleShutdown) # Ctrl+Break on Windows
+
+def configFileModifications():
+    # The modification time of every file the config was read from (None if it does not exist) and the files every include pattern matches
+    modifications = {path: os.stat(path).st_mtime if os.path.exists(path) else None for path in list(configFilesRead)}
+    modifications.update({pattern: sorted(glob.glob(pattern)) for pattern in list(configIncludeGlobs)})
+    return modifications
+
+def watchConfigFile():
+    global reloadRequested
+    lastModified = configFileModifications()
+    while not shutdownRequested.wait(2):
+        try:
+            modified = configFileModifications()
+        except OSError:
+            continue # E.g. while it is replaced
+        # Files only added by a reload (e.g. a new include) are compared from the next round on
+        changed = [path for path in modified if path in lastModified and modified[path] != lastModified[path]]
+        lastModified = modified
+        if len(changed):
+            logger.info(f'The config file changed ({", ".join(changed)}), reloading it...')
+            reloadRequested = True
+            wakeUp.set()
+
+if args.watch_config:
+    configWatchThread = threading.Thread(target=supervised('config_watch', watchConfigFile))
+    configWatchThread.daemon = True
+    configWatchThread.start()
+
+if args.simulate:
+    # Each step is one check: The external IP (or 'error') and optionally the expected published target afterwards
+    with open(args.simulate, 'r') as scenarioFile:
+        scenario = yaml.safe_load(scenarioFile)
+    simulationSteps = []
+    for step in scenario['steps']:
+        assert 'ip' in step, 'every simulation step should have an ip'
+        assert step.get('expect') in (None, 'primary', 'secondary', 'undefined'), 'simulation step expect should be primary, secondary or undefined'
+        simulationSteps += [step] * step.get('repeat', 1)
+    assert len(simulationSteps) > 0, 'simulation should have steps'
+    simulationIndex = 0
+    simulationFailures = []
+
+if args.shadow_config:
+    # The shadow runs the same election on the same external IPs, but never writes to Cloudflare or notifies
+    metricShadowCnameTarget = Enum(args.metrics_prefix + '_shadow_cname_target', 'Which CNAME would the shadow config publish?', states=['primary', 'secondary', 'undefined'], registry=metricRegistry)
+    metricShadowPrimaryConfidence = Gauge(args.metrics_prefix + '_shadow_primary_confidence', 'Primary confidence of the shadow config', registry=metricRegistry)
+    try:
+        shadowConfig = loadShadowConfig(args.shadow_config)
+    except Exception:
+        logger.exception('Could not load the shadow config!')
+        sys.exit(1)
+    shadowSubnets = parseSubnets(shadowConfig)
+    shadowConfidence = int(shadowConfig['primary']['confidence'] / 2)
+    shadowActive = None
+    metricShadowPrimaryConfidence.set_function(lambda: shadowConfidence)
+
+    def shadowCheck(ip):
+        # The election of the main loop with the shadow config, ip is None if the external IP could not be resolved
+        global shadowConfidence, shadowActive
+        shadowConfidence = nextPrimaryConfidence(shadowConfidence, 'error' if ip is None else subnetOwner(ip, shadowSubnets), shadowSubnets)
+        switchTo = electedTarget(shadowConfidence, shadowActive, shadowConfig['primary']['confidence'])
+        if switchTo is not None:
+            shadowActive = switchTo == 'primary'
+            recordEvent('shadow', f'Shadow config would switch to {switchTo} (primary confidence {shadowConfidence})')
+        metricShadowCnameTarget.state('undefined' if shadowActive is None else ('primary' if shadowActive else 'secondary'))
+
+def reportCrash(excType, excValue, excTraceback):
+    # Last words before the process dies, so a silent container restart does not go unnoticed
+    if issubclass(excType, KeyboardInterrupt):
+        sys.__excepthook__(excType, excValue, excTraceback)
+        return
+    logger.critical('Crashed!', exc_info=(excType, excValue, excTraceback))
+    if config['general']['crash_report_file'] is not None:
+        try:
+            with open(config['general']['crash_report_file'], 'a') as reportFile:
+                reportFile.write(f'Crash of {instanceId()} (version {version}) at {datetime.datetime.now(datetime.timezone.utc).isoformat()}\n')
+                reportFile.write(''.join(traceback.format_exception(excType, excValue, excTraceback)))
+                reportFile.write('State: ' + json.dumps({'target': currentTarget(), 'primary_confidence': primaryConfidence, 'external_ip': None if externalIPv4 is None else str(externalIPv4), 'last_cloudflare_update': lastCloudflareUpdate}) + '\n\n')
+        except Exception:
+            logger.exception('Could not write the crash report.')
+    if telegramToken is not None and not args.simulate:
+        for target in telegramTargets:
+            try:
+                deliverTelegramNotification(f'Switcher {instanceId()} crashed: {excType.__name__}: {excValue}', False, target) # Synchronously, the sender thread dies with us
+            except Exception:
+                logger.exception('Could not send the crash notification.')
+sys.excepthook = reportCrash
 
 logger.info('Startup complete.')
-oldExternalIPv4 = None
-externalIPv4 = None
-primaryActive = None
-ignoreFirstNotification = True
-notificationBuffer = [] # In case sending a notification failes, it will be stored here...
-if telegramToken is not None:
-    metricQueuedTelegramNotifications = Gauge(args.metrics_prefix + '_queued_telegram_notifications', 'How many Telegram notifications are queued?', registry=metricRegistry)
-    metricQueuedTelegramNotifications.set_function(lambda: len(notificationBuffer))
+metricQueuedTelegramNotifications = Gauge(args.metrics_prefix + '_queued_telegram_notifications', 'How many Telegram notifications are queued?', registry=metricRegistry)
+metricQueuedTelegramNotifications.set_function(lambda: len(notificationBuffer) + telegramQueue.qsize())
 try:
-    def sendTelegramNotification(message, markdown):
-        global ignoreFirstNotification, notificationBuffer, logger
+    def sendTelegramNotification(message, markdown, ignorable=True):
+        global ignoreFirstNotification
         if telegramToken is None:
             return
-        if ignoreFirstNotification:
+        if ignoreFirstNotification and ignorable:
             ignoreFirstNotification = False
             return
-        try:
-            req = Request('https://api.telegram.org/bot' + telegramToken + '/sendMessage', method='POST')
-            req.add_header('Content-Type', 'application/json')
-            data = { 'chat_id': telegramTarget }
-            if markdown:
-                data['parse_mode'] = 'MarkdownV2'
-                data['text'] = message.replace('.', '\\.')
-            else:
-                data['text'] = message
-            data = json.dumps(data)
-            data = data.encode()
-            with metricDurations.labels(dimension='send_telegram').time():
-                urlopen(req, timeout=config['general']['timeout'], data=data)
-            logger.info('Sent Telegram notification successfully: ' + message.replace('\n', ' '))
-            retryTelegramNotifications()
-        except:
-            notificationBuffer.append((message, markdown, datetime.datetime.now(datetime.timezone.utc)))
-            logger.exception('Telegram notification error.')
-
-    def retryTelegramNotifications():
-        global notificationBuffer, logger
-        if len(notificationBuffer):
-            retryThese = notificationBuffer
-            notificationBuffer = [] # Empty current buffer (prevent endless loop)
-            logger.info(f'Processing {len(retryThese)} delayed massages...')
-            notWorking = False
-            while len(retryThese):
-                msg, markdown, timestamp = retryThese.pop(0)
-                if markdown:
-                    msg += f'\n\n_This is a delayed message from `{timestamp.isoformat()}`._'
+        if args.simulate:
+            logger.info('[Simulation] Telegram notification: ' + message.replace('\n', ' '))
+            return
+        for target in telegramTargets:
+            telegramQueue.put((message, markdown, datetime.datetime.now(datetime.timezone.utc), False, target))
+
+    def deliverTelegramNotification(message, markdown, target):
+        data = dict(target)
+        if markdown:
+            data['parse_mode'] = 'MarkdownV2'
+            data['text'] = message.replace('.', '\\.')
+        else:
+            data['text'] = message
+        with metricDurations.labels(dimension='send_telegram').time():
+            openUrl(config, 'POST', 'https://api.telegram.org/bot' + telegramToken + '/sendMessage', data).close()
+
+    def telegramSender():
+        # Sends the notifications in their order on its own thread, so an unreachable Telegram never delays the checks
+        failures = 0
+        retryAt = 0
+        stopping = False
+        while True:
+            reportProgress('telegram_sender')
+            try:
+                # Wait for new notifications, or until the pending ones should be retried
+                notification = telegramQueue.get(timeout=None if len(notificationBuffer) == 0 else max(0, retryAt - time.monotonic()))
+                if notification is None:
+                    stopping = True # Shutdown - but try to send what was collected so far once more
                 else:
-                    msg += f'\n\nThis is a delayed message from {timestamp.isoformat()}.'
-                try:
-                    if not notWorking:
-                        sendTelegramNotification(msg, markdown) # This will re-queue the message on failure...
+                    notificationBuffer.append(notification)
+                    if failures == 0:
+                        continue # Collect everything queued meanwhile, then send
+            except queue.Empty:
+                pass
+            if stopping and len(notificationBuffer) == 0:
+                return
+            if not stopping and (len(notificationBuffer) == 0 or time.monotonic() < retryAt):
+                continue
+            failedTargets = []
+            retryAfterMax = 0 # Telegram tells us how long to wait when we send too much
+            for notification in list(notificationBuffer):
+                message, markdown, timestamp, delayed, target = notification
+                if target in failedTargets:
+                    continue # Keep the order within each chat, but do not block the others
+                if delayed:
+                    if markdown:
+                        message += f'\n\n_This is a delayed message from `{timestamp.isoformat()}`._'
                     else:
-                        notificationBuffer.append((msg, markdown, timestamp)) # Re-queue the message
-                except:
-                    notWorking = True # Stop sending messages until it works again...
-                    pass # Well... The notification failed and was re-queued. Nothing we can do about it...
+                        message += f'\n\nThis is a delayed message from {timestamp.isoformat()}.'
+                try:
+                    reportProgress('telegram_sender', 0) # Busy now
+                    deliverTelegramNotification(message, markdown, target)
+                except Exception as e:
+                    retryable, retryAfter = classifyHttpError(e)
+                    if not retryable:
+                        # E.g. the bot was removed from the chat, so retrying would block the chat forever
+                        notificationBuffer.remove(notification)
+                        logger.exception(f'Telegram notification error (chat {target["chat_id"]}), dropping it as it is not retryable.')
+                        recordEvent('notification', f'Telegram notification dropped: {e}')
+                        continue
+                    failedTargets.append(target)
+                    if retryAfter is not None:
+                        retryAfterMax = max(retryAfterMax, retryAfter)
+                    logger.exception(f'Telegram notification error (chat {target["chat_id"]}).')
+                    continue
+                notificationBuffer.remove(notification)
+                logger.info('Sent Telegram notification successfully: ' + message.replace('\n', ' '))
+                recordEvent('notification', 'Sent Telegram notification: ' + message.replace('\n', ' '))
+            if len(failedTargets):
+                failures += 1
+                retryAt = time.monotonic() + max(min(5 * 2 ** (failures - 1), 600), retryAfterMax) # Back off up to 10 minutes
+                notificationBuffer[:] = [n[:3] + (n[3] or n[4] in failedTargets,) + n[4:] for n in notificationBuffer] # These are late now
+                logger.warning(f'Retrying {len(notificationBuffer)} Telegram notifications in {retryAt - time.monotonic():.0f}s...')
+                recordEvent('notification', f'Telegram notification failed, {len(notificationBuffer)} queued for retry')
+            else:
+                failures = 0
+            if stopping:
+                return
+
+    telegramSenderThread = threading.Thread(target=supervised('telegram_sender', telegramSender))
+    telegramSenderThread.daemon = True
+    telegramSenderThread.start()
+
+    while not shutdownRequested.is_set():
+        reportProgress('main_loop', loopTime) # The check itself is covered by watchdog.grace
+        reconcileNow, reconcileRequested = reconcileRequested, False
+        if reconcileNow:
+            logger.info('Reconciling the records with Cloudflare after this check...')
+            oldExternalIPv4 = None # Re-publish the A-record
+
+        # Apply a changed config before the next check...
+        loaded = None
+        if reloadRequested:
+            reloadRequested = False
+            with reloadWaitersLock:
+                waiters, reloadWaiters = reloadWaiters, []
+            try:
+                loaded = loadConfig(args.config)
+                outcome = {'success': True, 'changed_sections': sorted(k for k in set(loaded[0]) | set(config) if loaded[0].get(k) != config.get(k))}
+            except Exception as e:
+                logger.exception('Could not reload the config, keeping the current one.')
+                recordEvent('config', f'Reloading the config failed: {e}')
+                outcome = {'success': False, 'error': f'{type(e).__name__}: {e}' + ('' if e.__cause__ is None else f' ({type(e.__cause__).__name__}: {e.__cause__})')}
+            if args.shadow_config:
+                try:
+                    reloadedShadowConfig = loadShadowConfig(args.shadow_config)
+                    if configChanged(shadowConfig, reloadedShadowConfig, 'primary', 'secondary'):
+                        shadowConfidence = int(reloadedShadowConfig['primary']['confidence'] / 2)
+                        shadowActive = None
+                    shadowConfig = reloadedShadowConfig
+                    shadowSubnets = parseSubnets(shadowConfig)
+                    logger.info('Reloaded the shadow config.')
+                except Exception:
+                    logger.exception('Could not reload the shadow config, keeping the current one.')
+            for waiter in waiters:
+                waiter['outcome'] = outcome
+                waiter['done'].set()
+        if loaded is not None:
+            # The new config is already validated, so the previous one stays active until here
+            previousConfig = config
+            applyConfig(*loaded)
+            metricConfigReloadSuccess.set_to_current_time()
+            updateInfoMetrics()
+            # Only start from scratch where the config changed, so e.g. the primary confidence survives unrelated changes
+            resetState = []
+            if configChanged(previousConfig, config, 'cloudflare.zone_id', 'cloudflare.token', 'dyndns'):
+                oldExternalIPv4 = None # Re-publish the A-record
+                resetState.append('dyndns')
+            if configChanged(previousConfig, config, 'cloudflare.zone_id', 'cloudflare.token', 'general.dynamic_cname', 'general.record_type', 'general.mx_priority', 'primary', 'secondary'):
+                primaryConfidence = int(config['primary']['confidence'] / 2)
+                primaryActive = None # Re-publish the CNAME
+                metricCnameTarget.state('undefined')
+                resetState.append('cname')
+            if configChanged(previousConfig, config, 'pin'):
+                pinnedTarget = pinFromConfig(config) # Also replaces a pin set via /admin/pin
+                resetState.append('pin')
+            logger.info(f'Reloaded the config (reset state: {", ".join(resetState) or "none"}).')
+            recordEvent('config', f'Reloaded the config (reset state: {", ".join(resetState) or "none"})')
+
+        # Refresh the secrets from Vault (if any)...
+        if len(secretReferences) and time.monotonic() >= secretsRefreshAt:
+            try:
+                refreshedConfig = copy.deepcopy(config)
+                resolveSecrets(refreshedConfig, secretReferences)
+                applyConfig(refreshedConfig, CloudflareDnsRecordId, CloudflareDynDnsRecordId, secretReferences)
+                logger.debug('Refreshed the secrets from Vault.')
+            except Exception:
+                logger.exception('Could not refresh the secrets from Vault, keeping the current ones.')
+                secretsRefreshAt = time.monotonic() + config['vault']['refresh_interval']
 
-    while True:
         # Get the external ip and validate primary cname allowance
         with metricDurations.labels(dimension='loop').time():
+            resolveStart = time.monotonic()
+            resolveLatency = None
             try:
                 logger.debug('Resolving external IPv4...')
                 with metricDurations.labels(dimension='external_ip').time():
-                    if config['general']['external_resolver'] == 'default':
-                        externalIPv4 = ipaddress.ip_address(str(getter.get().v4))
+                    if args.simulate:
+                        simulationStep = simulationSteps[simulationIndex]
+                        if simulationStep['ip'] == 'error':
+                            raise ConnectionError('Simulated external IPv4 resolve error')
+                        externalIPv4 = ipaddress.ip_address(simulationStep['ip'])
+                    elif config['general']['external_resolver'].startswith('interface:'):
+                        externalIPv4 = withRetries(config, 'Reading the interface address', lambda: interfaceAddress(config['general']['external_resolver'].split(':', 1)[1]), lambda e: (True, None))
+                    elif config['general']['external_resolver'] == 'default':
+                        externalIPv4 = ipaddress.ip_address(str(withRetries(config, 'Resolving the external IPv4', getter.get, lambda e: (True, None)).v4))
                     else:
-                        externalIPv4 = ipaddress.ip_address(str(getter.get_from(config['general']['external_resolver']).v4))
+                        externalIPv4 = ipaddress.ip_address(str(withRetries(config, 'Resolving the external IPv4', lambda: getter.get_from(config['general']['external_resolver']), lambda e: (True, None)).v4))
+                    if chaosSettings['enabled'] and chaosSettings['resolver_delay'] > 0:
+                        shutdownRequested.wait(chaosSettings['resolver_delay']) # Injected delay
+                    injectChaos('resolver_failure_probability', 'Injected external IPv4 resolve failure')
+                resolveLatency = time.monotonic() - resolveStart
+                if config['general']['max_latency'] is not None and resolveLatency > config['general']['max_latency']:
+                    # A saturated uplink may still "work", but should not be preferred
+                    raise LatencyExceededError(f'Resolving the external IPv4 took {resolveLatency:.1f}s (more than {config["general"]["max_latency"]}s)')
                 
                 if externalIPv4 == ipaddress.IPv4Address('0.0.0.0'):
                     raise ValueError('External IPv4 is empty (0.0.0.0). Something seems wrong...')
                 metricExternalIp.info({'ip': str(externalIPv4)})
 
                 # Update the cname to the external ip...
-                if CloudflareDynDnsRecordId is not None and oldExternalIPv4 != externalIPv4:
+                dyndnsHeld = False
+                if oldExternalIPv4 == externalIPv4:
+                    dyndnsCandidate = None # The IP came back -> a later change must be held down from its own start
+                elif CloudflareDynDnsRecordId is not None and oldExternalIPv4 is not None:
+                    # Damp changes of the IP within the same uplink, but follow a switch to the other uplink right away
+                    if dyndnsCandidate is None or dyndnsCandidate[0] != externalIPv4:
+                        dyndnsCandidate = (externalIPv4, time.monotonic())
+                    if subnetOwner(oldExternalIPv4, configSubnets) == subnetOwner(externalIPv4, configSubnets) and time.monotonic() - dyndnsCandidate[1] < config['dyndns']['dyndns_hold_down']:
+                        logger.debug(f'Holding down the A-record update to {externalIPv4} (still {oldExternalIPv4})...')
+                        dyndnsHeld = True
+                if CloudflareDynDnsRecordId is not None and oldExternalIPv4 != externalIPv4 and not dyndnsHeld and not backingOffFrom(config['dyndns']['dyndns_target']):
                     try:
                         data = {
                             'type': 'A',
@@ -209,103 +2542,180 @@ try:
                             'ttl': config['dyndns']['dyndns_ttl'],
                             'proxied': False
                         }
-                        request = Request(
-                            'https://api.cloudflare.com/client/v4/zones/' + config['cloudflare']['zone_id'] + '/dns_records/' + CloudflareDynDnsRecordId,
-                            method='PUT',
-                            data=bytes(json.dumps(data), encoding='utf8'),
-                            headers={
-                                'Authorization': 'Bearer ' + config['cloudflare']['token'],
-                                'Content-Type': 'application/json'
-                            }
-                        )
                         with metricDurations.labels(dimension='dyndns').time():
-                            urlopen(request, timeout=config['general']['timeout'])
+                            updateOwnedRecord(CloudflareDynDnsRecordId, data, 'external IP published' if oldExternalIPv4 is None else f'external IP was {oldExternalIPv4}')
                         logger.info('Updated ' + config['dyndns']['dyndns_target'] + ' to ' + data['content'])
+                        recordCloudflareUpdate(config['dyndns']['dyndns_target'], data['content'])
+                        checkPropagation(config['dyndns']['dyndns_target'], 'A', data['content'])
                         oldExternalIPv4 = externalIPv4 # Will be retried if not successful
+                        dyndnsCandidate = None
+                    except InstanceConflictError:
+                        pass # Already reported, retried once general.instance_conflict_window expired
                     except Exception as e:
                         logger.exception('Cloudflare A-record update error.')
+                        recordCloudflareUpdate(config['dyndns']['dyndns_target'], str(externalIPv4), e)
                         sendTelegramNotification(f'Something went wrong at the Cloudflare A-record updater: {e}', False)
                 
-                externalIsPrimary = True in [externalIPv4 in n for n in primarySubnets]
-                externalIsSecondary = True in [externalIPv4 in n for n in secondarySubnets]
-                logger.debug(f'IP-Owner? externalIsPrimary {externalIsPrimary}, externalIsSecondary {externalIsSecondary}')
-                if externalIsPrimary or (not primarySubnetsGiven and not externalIsSecondary):
-                    primaryConfidence += 1
-                elif externalIsSecondary or (not secondarySubnetsGiven and not externalIsPrimary):
-                    primaryConfidence = 0
-                else:
+                owner = subnetOwner(externalIPv4, configSubnets)
+                logger.debug(f'IP-Owner? {owner}')
+                if owner != externalIpOwner:
+                    recordEvent('health', f'External IP {externalIPv4} is now owned by: {owner}')
+                    externalIpOwner = owner
+                primaryConfidence = nextPrimaryConfidence(primaryConfidence, owner, configSubnets)
+                if owner == 'none' and primarySubnetsGiven and secondarySubnetsGiven:
                     logger.warning('External IP (' + str(externalIPv4) + ') is in neither the primary (' + str(primarySubnets) + ') nor the secondary (' + str(secondarySubnets) + ') subnet -> ignoring...')
                 logger.debug('External IP is ' + str(externalIPv4))
+                recordCheck(resolveLatency)
             except Exception as e:
                 logger.exception('External IPv4 resolve error.')
+                recordEvent('health', f'External IPv4 resolve error: {e}')
+                recordResolveProblem(e)
+                if externalIpOwner != 'error':
+                    sendSnmpTrap('outage', {'record': config['general']['dynamic_cname'], 'target': currentTarget(), 'reason': str(e)})
+                externalIpOwner = 'error'
+                recordCheck(resolveLatency or time.monotonic() - resolveStart, e)
                 primaryConfidence = 0
                 sendTelegramNotification(f'Something went wrong at the external IPv4 resolver: {e}', False)
+            accountUplinkStates()
+            if args.shadow_config:
+                shadowCheck(None if externalIpOwner == 'error' else externalIPv4)
 
             # And update the dns entry of Cloudflare...
-            def updateDynamicCname(config, data) -> bool:
+            def updateDynamicCname(config, data, reason):
+                # Returns why the update was not applied, or None
+                global publishedCnameTtl
                 try:
-                    request = Request(
-                        'https://api.cloudflare.com/client/v4/zones/' + config['cloudflare']['zone_id'] + '/dns_records/' + CloudflareDnsRecordId,
-                        method='PUT',
-                        data=bytes(json.dumps(data), encoding='utf8'),
-                        headers={
-                            'Authorization': 'Bearer ' + config['cloudflare']['token'],
-                            'Content-Type': 'application/json'
-                        }
-                    )
                     with metricDurations.labels(dimension='cname_update').time():
-                        urlopen(request, timeout=config['general']['timeout'])
+                        updateOwnedRecord(CloudflareDnsRecordId, data, reason)
                     logger.info('Updated ' + config['general']['dynamic_cname'] + ' to ' + data['content'])
-                    return True
+                    recordCloudflareUpdate(config['general']['dynamic_cname'], data['content'])
+                    checkPropagation(config['general']['dynamic_cname'], data['type'], data['content'])
+                    publishedCnameTtl = data['ttl']
+                    return None
+                except InstanceConflictError as e:
+                    return e # Already reported and not a failure of Cloudflare
                 except Exception as e:
                     logger.exception('Cloudflare CNAME-record update error.')
+                    recordCloudflareUpdate(config['general']['dynamic_cname'], data['content'], e)
                     sendTelegramNotification(f'Something went wrong at the Cloudflare CNAME updater: {e}', False)
-                    return False
-
-            if primaryConfidence >= config['primary']['confidence'] and primaryActive != True:
-                data = {
-                    'type': 'CNAME',
-                    'name': config['general']['dynamic_cname'],
-                    'content': config['primary']['cname'],
-                    'ttl': config['primary']['ttl'],
-                    'proxied': False
-                }
-                if updateDynamicCname(config, data):
+                    return e
+
+            nextPlannedStep = advancePlannedFailovers()
+            pin = pinnedTarget # /admin/pin may replace it meanwhile
+            if pin is not None and pinTarget(pin) is None:
+                what = 'Planned failover' if pin['source'] == 'planned' else 'Pin'
+                logger.info(f'{what} to {pin["target"]} ended.')
+                recordEvent('election', f'{what} to {pin["target"]} ended, the election decides again')
+                sendTelegramNotification(f'{what} to {pin["target"]} *ENDED*. The election decides again.', True)
+                pinnedTarget = pin = None
+            # While another instance owns the record, the failed update left primaryActive undefined - so nothing else is tried meanwhile
+            electedNow = None if backingOffFrom(config['general']['dynamic_cname']) else electedTarget(primaryConfidence, primaryActive, config['primary']['confidence'])
+            pinned = electedNow is not None and electedNow == pinTarget(pin)
+            pinnedUntil = '' if not pinned or pin['until'] is None else f' until {pin["until"]}'
+            if electedNow == 'primary':
+                data = dynamicCnameData(config, 'primary')
+                previousTarget = currentTarget()
+                error = updateDynamicCname(config, data, 'pinned to primary' if pinned else f'primary stable for {primaryConfidence} checks')
+                if error is None:
                     metricCnameTarget.state('primary')
                     primaryActive = True
-                    sendTelegramNotification(f'Primary network connection *STABLE* since `{primaryConfidence}` checks. Failover INACTIVE. Current IPv4 is `{externalIPv4}`.', True)
+                    if pinned:
+                        recordEvent('election', f'Switched to primary (pinned{pinnedUntil})')
+                        sendTelegramNotification(f'Switched to primary, as it is *PINNED*{pinnedUntil}. Current IPv4 is `{externalIPv4}`.', True)
+                    else:
+                        recordEvent('election', f'Switched to primary after {primaryConfidence} stable checks')
+                        sendTelegramNotification(f'Primary network connection *STABLE* since `{primaryConfidence}` checks. Failover INACTIVE. Current IPv4 is `{externalIPv4}`.', True)
+                    sendSnmpTrap('failover', {'record': config['general']['dynamic_cname'], 'previous_target': previousTarget, 'target': 'primary', 'reason': 'pinned' if pinned else 'primary confidence reached'})
                 else:
                     # CNAME update failed -> undefined state
                     metricCnameTarget.state('undefined')
                     primaryActive = None
-            elif primaryConfidence == 0 and primaryActive != False:
-                data = {
-                    'type': 'CNAME',
-                    'name': config['general']['dynamic_cname'],
-                    'content': config['secondary']['cname'],
-                    'ttl': config['secondary']['ttl'],
-                    'proxied': False
-                }
-                if updateDynamicCname(config, data):
+                recordDecision('pinned' if pinned else 'primary confidence reached', previousTarget, data, error)
+            elif electedNow == 'secondary':
+                data = dynamicCnameData(config, 'secondary')
+                previousTarget = currentTarget()
+                if pinned:
+                    reason = 'pinned to secondary'
+                elif externalIpOwner == 'error':
+                    reason = 'external IP unresolvable' + ('' if lastResolveProblem is None else f' ({lastResolveProblem["reason"]})')
+                else:
+                    reason = f'external IP {externalIPv4} not primary'
+                error = updateDynamicCname(config, data, reason)
+                if error is None:
                     metricCnameTarget.state('secondary')
                     primaryActive = False
-                    sendTelegramNotification(f'Primary network connection *FAILED*. Failover ACTIVE. Recheck in `{loopTime}` seconds... Current IPv4 is `{externalIPv4}`.', True)
+                    if pinned:
+                        recordEvent('election', f'Switched to secondary (pinned{pinnedUntil})')
+                        sendTelegramNotification(f'Switched to secondary, as it is *PINNED*{pinnedUntil}. Current IPv4 is `{externalIPv4}`.', True)
+                    else:
+                        recordEvent('election', 'Switched to secondary (failover active)')
+                        sendTelegramNotification(f'Primary network connection *FAILED*. Failover ACTIVE. Recheck in `{loopTime}` seconds... Current IPv4 is `{externalIPv4}`.', True)
+                    sendSnmpTrap('failover', {'record': config['general']['dynamic_cname'], 'previous_target': previousTarget, 'target': 'secondary', 'reason': 'pinned' if pinned else 'primary confidence lost'})
                 else:
                     # CNAME update failed -> undefined state
                     metricCnameTarget.state('undefined')
                     primaryActive = None
+                recordDecision('pinned' if pinned else 'primary confidence lost', previousTarget, data, error)
+            elif reconcileNow and primaryActive is not None:
+                # Nothing to switch, but the record at Cloudflare may have been changed by someone else
+                data = dynamicCnameData(config, currentTarget())
+                previousTarget = currentTarget()
+                error = updateDynamicCname(config, data, 'reconcile requested')
+                if error is None:
+                    recordEvent('election', f'Re-published {previousTarget} (reconcile requested)')
+                else:
+                    metricCnameTarget.state('undefined')
+                    primaryActive = None
+                recordDecision('reconcile requested', previousTarget, data, error)
+            elif primaryActive is not None and dynamicCnameData(config, currentTarget())['ttl'] != publishedCnameTtl:
+                # Same target, but e.g. general.ttl_strategy dynamic raises the TTL again after a stable period
+                data = dynamicCnameData(config, currentTarget())
+                previousTarget = currentTarget()
+                error = updateDynamicCname(config, data, f'TTL adjusted to {data["ttl"]}s')
+                if error is None:
+                    recordEvent('election', f'Adjusted the TTL of {previousTarget} to {data["ttl"]}s')
+                else:
+                    metricCnameTarget.state('undefined')
+                    primaryActive = None
+                recordDecision('TTL adjusted', previousTarget, data, error)
             logger.debug('primaryConfidence? ' + str(primaryConfidence))
-
-            # Retry the remaining messages...
-            retryTelegramNotifications()
             
             HealthcheckMetricEndpoint.lastLoop = datetime.datetime.now()
 
+        if args.simulate:
+            simulationIndex += 1
+            logger.info(f'[Simulation] Step {simulationIndex}/{len(simulationSteps)}: External IP {simulationStep["ip"]} -> {currentTarget()} (primary confidence {primaryConfidence})')
+            if simulationStep.get('expect') is not None and simulationStep['expect'] != currentTarget():
+                logger.error(f'[Simulation] Step {simulationIndex} expected {simulationStep["expect"]}, but got {currentTarget()}!')
+                simulationFailures.append(simulationIndex)
+            if simulationIndex == len(simulationSteps):
+                break
+            continue # No need to wait
+
         # Wait until next check...
         logger.debug('Sleeping...')
-        time.sleep(loopTime)
+        wakeUp.wait(loopTime if nextPlannedStep is None else min(loopTime, max(0, nextPlannedStep)))
+        wakeUp.clear()
 except KeyboardInterrupt:
     pass
         
 logger.info('Bye!')
-healthcheckServer.shutdown() # stop the healthcheck server
\ No newline at end of file
+shutdownRequested.set()
+for name in uplinkStates:
+    storeHistory('uplinks', datetime.datetime.now(datetime.timezone.utc).isoformat(), name, 'unknown') # Not monitored until the next start
+telegramQueue.put(None) # Stops the sender once the notifications queued before are sent
+telegramSenderThread.join(config['general']['timeout'])
+with eventSubscribersLock:
+    for subscriber in eventSubscribers:
+        try:
+            subscriber.put_nowait(None) # Ends the stream
+        except queue.Full:
+            pass # The stream checks shutdownRequested on its own
+if config['metrics']['push_gateway'] is not None:
+    pushMetrics() # The final state, e.g. of a one-shot run
+for healthcheckServer in healthcheckServers:
+    healthcheckServer.shutdown() # stop the healthcheck server
+    healthcheckServer.server_close()
+if args.simulate:
+    logger.info(f'[Simulation] {len(simulationSteps) - len(simulationFailures)}/{len(simulationSteps)} steps as expected.')
+    sys.exit(1 if len(simulationFailures) else 0)
\ No newline at end of file
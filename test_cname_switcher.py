@@ -0,0 +1,108 @@
+import dataclasses
+import datetime
+import importlib.util
+import os
+import sys
+import tempfile
+import unittest
+import yaml
+
+def loadSwitcher():
+    # The script runs on import - so let it simulate a single check (Cloudflare is SimulatedCloudflareApi then) and keep its functions afterwards
+    directory = tempfile.mkdtemp()
+    configPath = os.path.join(directory, 'config.yml')
+    scenarioPath = os.path.join(directory, 'scenario.yml')
+    with open(configPath, 'w') as configFile:
+        yaml.safe_dump({
+            'cloudflare': {'zone_id': 'zone', 'token': 'token'},
+            'general': {'dynamic_cname': 'dyn.example.com', 'update_interval': 1},
+            'primary': {'cname': 'primary.example.com', 'subnets': ['1.2.3.0/24'], 'confidence': 4},
+            'secondary': {'cname': 'secondary.example.com', 'subnets': ['5.6.7.0/24']}
+        }, configFile)
+    with open(scenarioPath, 'w') as scenarioFile:
+        yaml.safe_dump({'steps': [{'ip': '1.2.3.4'}]}, scenarioFile)
+    argv = sys.argv
+    sys.argv = ['cname_switcher.py', '--config', configPath, '--simulate', scenarioPath, '--bind', '127.0.0.1:0']
+    spec = importlib.util.spec_from_file_location('cname_switcher', os.path.join(os.path.dirname(os.path.abspath(__file__)), 'cname_switcher.py'))
+    switcher = importlib.util.module_from_spec(spec)
+    try:
+        spec.loader.exec_module(switcher)
+    except SystemExit as e:
+        assert not e.code, f'the simulated check failed ({e.code})'
+    finally:
+        sys.argv = argv
+        sys.excepthook = sys.__excepthook__
+    return switcher
+
+switcher = loadSwitcher()
+
+class ElectionTest(unittest.TestCase):
+    def setUp(self):
+        self.subnets = switcher.parseSubnets(switcher.config)
+
+    def tearDown(self):
+        switcher.pinnedTarget = None
+
+    def pin(self, target, until=None):
+        switcher.pinnedTarget = {'target': target, 'until': None if until is None else until.isoformat(), 'source': 'test'}
+
+    def testConfidence(self):
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'primary', self.subnets), 3)
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'secondary', self.subnets), 0)
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'error', self.subnets), 0)
+
+    def testNeitherSubnetIsIgnored(self):
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'none', self.subnets), 2)
+        # Without secondary subnets, everything not primary counts as secondary
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'none', (self.subnets[0], [])), 0)
+        self.assertEqual(switcher.nextPrimaryConfidence(2, 'none', ([], self.subnets[1])), 3)
+
+    def testConfidenceThreshold(self):
+        self.assertIsNone(switcher.electedTarget(3, False, 4))
+        self.assertEqual(switcher.electedTarget(4, False, 4), 'primary')
+        self.assertEqual(switcher.electedTarget(4, None, 4), 'primary')
+        self.assertIsNone(switcher.electedTarget(5, True, 4)) # Already published
+        self.assertEqual(switcher.electedTarget(0, True, 4), 'secondary')
+        self.assertIsNone(switcher.electedTarget(0, False, 4))
+
+    def testPins(self):
+        self.pin('secondary')
+        self.assertEqual(switcher.electedTarget(10, True, 4), 'secondary')
+        self.assertIsNone(switcher.electedTarget(10, False, 4))
+        self.pin('primary')
+        self.assertEqual(switcher.electedTarget(1, False, 4), 'primary') # Without waiting for the confidence...
+        self.assertIsNone(switcher.electedTarget(0, False, 4)) # ...but only while the checks see the primary
+
+    def testExpiredPin(self):
+        self.pin('secondary', datetime.datetime.now(datetime.timezone.utc) - datetime.timedelta(minutes=1))
+        self.assertIsNone(switcher.activePin())
+        self.assertIsNone(switcher.electedTarget(10, True, 4))
+
+class UpdateTest(unittest.TestCase):
+    def tearDown(self):
+        switcher.instanceConflicts.clear()
+        switcher.cloudflare.records[self.publishedRecord().name] = dataclasses.replace(self.publishedRecord(), comment=None) # Ours again
+
+    def publishedRecord(self):
+        return switcher.cloudflare.listRecords(switcher.config['general']['dynamic_cname'], 'CNAME')[0]
+
+    def testRoundTrip(self):
+        for target in ('secondary', 'primary'):
+            data = switcher.dynamicCnameData(switcher.config, target)
+            self.assertIsNone(switcher.updateDynamicCname(switcher.config, data, 'test'))
+            record = self.publishedRecord()
+            self.assertEqual(record.content, switcher.config[target]['cname'])
+            self.assertEqual(record.ttl, data['ttl'])
+            self.assertTrue(record.comment.startswith(f'cname-switcher {switcher.instanceId()} '))
+
+    def testInstanceConflict(self):
+        self.assertIsNone(switcher.updateDynamicCname(switcher.config, switcher.dynamicCnameData(switcher.config, 'primary'), 'test'))
+        otherComment = f'cname-switcher other {datetime.datetime.now(datetime.timezone.utc).strftime("%Y-%m-%dT%H:%M:%SZ")}'
+        switcher.cloudflare.records[self.publishedRecord().name] = dataclasses.replace(self.publishedRecord(), comment=otherComment)
+        error = switcher.updateDynamicCname(switcher.config, switcher.dynamicCnameData(switcher.config, 'secondary'), 'test')
+        self.assertIsInstance(error, switcher.InstanceConflictError)
+        self.assertEqual(self.publishedRecord().content, switcher.config['primary']['cname']) # Left to the other instance
+        self.assertTrue(switcher.backingOffFrom(switcher.config['general']['dynamic_cname']))
+
+if __name__ == '__main__':
+    unittest.main()